@@ -1,5 +1,5 @@
 use std::{
-    io,
+    io::{self, Write},
     sync::mpsc::{self},
     thread,
     time::Duration,
@@ -18,7 +18,7 @@ use ratatui::{
 };
 use ratatui_image::{
     picker::Picker,
-    protocol::StatefulProtocol,
+    protocol::{kitty, StatefulProtocol},
     thread::{ThreadImage, ThreadProtocol},
     Resize,
 };
@@ -29,7 +29,7 @@ struct App {
 
 enum AppEvent {
     KeyEvent(KeyEvent),
-    Redraw(StatefulProtocol),
+    Redraw(Box<StatefulProtocol>),
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -53,8 +53,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let tx_main_render = tx_main.clone();
     thread::spawn(move || loop {
         if let Ok((mut protocol, resize, area)) = rec_worker.recv() {
-            protocol.resize_encode(&resize, protocol.background_color(), area);
-            tx_main_render.send(AppEvent::Redraw(protocol)).unwrap();
+            // On error, the protocol keeps it in `last_error()` and renders nothing.
+            let _ = protocol.resize_encode(&resize, protocol.background_color(), area);
+            tx_main_render
+                .send(AppEvent::Redraw(Box::new(protocol)))
+                .unwrap();
         }
     });
 
@@ -79,18 +82,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     loop {
         terminal.draw(|f| ui(f, &mut app))?;
+        // Kitty images are transmitted after the frame, outside of the buffer.
+        if let Some(seq) = kitty::take_pending_sequences() {
+            terminal.backend_mut().write_all(seq.as_bytes())?;
+            terminal.backend_mut().flush()?;
+        }
 
         if let Ok(ev) = rec_main.try_recv() {
             match ev {
                 AppEvent::KeyEvent(key) => {
-                    if key.kind == KeyEventKind::Press {
-                        if key.code == KeyCode::Char('q') {
-                            break;
-                        }
+                    if key.kind == KeyEventKind::Press && key.code == KeyCode::Char('q') {
+                        break;
                     }
                 }
                 AppEvent::Redraw(protocol) => {
-                    app.async_state.set_protocol(protocol);
+                    app.async_state.set_protocol(*protocol);
                 }
             }
         }
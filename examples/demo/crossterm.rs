@@ -14,7 +14,7 @@ use ratatui::{
     Terminal,
 };
 
-use crate::{ui, App};
+use crate::{ui, write_pending_sequences, App};
 
 pub fn run() -> Result<(), Box<dyn Error>> {
     let original_hook = std::panic::take_hook();
@@ -56,6 +56,7 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<(
     let mut last_tick = Instant::now();
     loop {
         terminal.draw(|f| ui(f, &mut app))?;
+        write_pending_sequences()?;
 
         let timeout = app
             .tick_rate
@@ -12,7 +12,14 @@ mod termion;
 #[cfg(feature = "termwiz")]
 mod termwiz;
 
-use std::{env, error::Error, num::Wrapping as w, path::PathBuf, time::Duration};
+use std::{
+    env,
+    error::Error,
+    io::{self, Write},
+    num::Wrapping as w,
+    path::PathBuf,
+    time::Duration,
+};
 
 use image::DynamicImage;
 use ratatui::{
@@ -25,7 +32,7 @@ use ratatui::{
 };
 use ratatui_image::{
     picker::Picker,
-    protocol::{Protocol, StatefulProtocol},
+    protocol::{kitty, Protocol, StatefulProtocol},
     Image, Resize, StatefulImage,
 };
 
@@ -65,6 +72,16 @@ struct App {
     image_scale_state: StatefulProtocol,
 }
 
+/// Write the Kitty sequences that are not part of the buffer, after every frame.
+fn write_pending_sequences() -> io::Result<()> {
+    if let Some(seq) = kitty::take_pending_sequences() {
+        let mut stdout = io::stdout();
+        stdout.write_all(seq.as_bytes())?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
 fn size() -> Rect {
     Rect::new(0, 0, 30, 16)
 }
@@ -80,7 +97,7 @@ impl App {
         let image_source = image::io::Reader::open(ada).unwrap().decode().unwrap();
 
         let mut picker = Picker::from_query_stdio().unwrap();
-        // Set completely transparent background (experimental, only works for iTerm2 and Kitty).
+        // Set completely transparent background (experimental, does not work for Halfblocks).
         picker.set_background_color([0, 0, 0, 0]);
 
         let image_static = picker
@@ -155,27 +172,19 @@ impl App {
                 self.reset_images();
             }
             'H' => {
-                if self.split_percent >= 10 {
-                    self.split_percent -= 10;
-                }
+                self.split_percent = self.split_percent.saturating_sub(10);
             }
             'L' => {
-                if self.split_percent <= 90 {
-                    self.split_percent += 10;
-                }
+                self.split_percent = (self.split_percent + 10).min(100);
             }
             'h' => {
-                if self.image_static_offset.0 > 0 {
-                    self.image_static_offset.0 -= 1;
-                }
+                self.image_static_offset.0 = self.image_static_offset.0.saturating_sub(1);
             }
             'j' => {
                 self.image_static_offset.1 += 1;
             }
             'k' => {
-                if self.image_static_offset.1 > 0 {
-                    self.image_static_offset.1 -= 1;
-                }
+                self.image_static_offset.1 = self.image_static_offset.1.saturating_sub(1);
             }
             'l' => {
                 self.image_static_offset.0 += 1;
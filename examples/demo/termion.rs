@@ -11,7 +11,7 @@ use ratatui::{
     Terminal,
 };
 
-use crate::{ui, App};
+use crate::{ui, write_pending_sequences, App};
 
 pub fn run() -> Result<(), Box<dyn Error>> {
     // setup terminal
@@ -35,6 +35,7 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> Result<(), B
     let events = events(app.tick_rate);
     loop {
         terminal.draw(|f| ui(f, &mut app))?;
+        write_pending_sequences()?;
 
         match events.recv()? {
             Event::Input(key) => {
@@ -10,7 +10,7 @@ use ratatui::{
     Terminal,
 };
 
-use crate::{ui, App};
+use crate::{ui, write_pending_sequences, App};
 
 pub fn run() -> Result<(), Box<dyn Error>> {
     let backend = TermwizBackend::new()?;
@@ -35,6 +35,7 @@ fn run_app(terminal: &mut Terminal<TermwizBackend>, mut app: App) -> io::Result<
     let mut last_tick = Instant::now();
     loop {
         terminal.draw(|f| ui(f, &mut app))?;
+        write_pending_sequences()?;
 
         let timeout = app
             .tick_rate
@@ -1,5 +1,6 @@
 use std::{
-    assert_eq, env, io,
+    assert_eq, env,
+    io::{self, Write},
     process::{Command, Stdio},
 };
 
@@ -15,7 +16,11 @@ use ratatui::{
     widgets::{Block, Borders, Paragraph},
     Frame, Terminal,
 };
-use ratatui_image::{picker::Picker, protocol::Protocol, Image, Resize};
+use ratatui_image::{
+    picker::Picker,
+    protocol::{kitty, Protocol},
+    Image, Resize,
+};
 struct App {
     image: Protocol,
 }
@@ -53,23 +58,30 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut app = App { image };
 
     terminal.draw(|f| ui(f, &mut app))?;
+    // Kitty images are transmitted after the frame, outside of the buffer.
+    if let Some(seq) = kitty::take_pending_sequences() {
+        terminal.backend_mut().write_all(seq.as_bytes())?;
+        terminal.backend_mut().flush()?;
+    }
     std::thread::sleep(std::time::Duration::from_secs(1)); // let the terminal actually draw.
-    let xwd = Command::new("xwd")
+    let mut xwd = Command::new("xwd")
         .args(["-root", "-silent"])
         .stdout(Stdio::piped())
         .spawn()
         .expect("Failed to start xwd command");
     let screenshot_term = env::var("SCREENSHOT_TERM_NAME").unwrap_or("unknown".to_string());
-    std::process::Command::new("convert")
+    let convert = std::process::Command::new("convert")
         .args([
             "xwd:-",
             &format!("png:./target/screenshot_{screenshot_term}.png"),
         ])
-        .stdin(xwd.stdout.expect("failed to get stdout"))
+        .stdin(xwd.stdout.take().expect("failed to get stdout"))
         .stdout(std::process::Stdio::null())
         .stderr(std::process::Stdio::null())
         .spawn()
-        .and_then(|mut child| child.wait())?;
+        .and_then(|mut child| child.wait());
+    xwd.wait()?;
+    convert?;
 
     // restore terminal
     disable_raw_mode()?;
@@ -0,0 +1,342 @@
+//! Animated image widget that works with any protocol.
+//!
+//! Sixel, iTerm2 and Halfblocks have no terminal-side animation, so the frames have to be
+//! rendered one after the other by the application. The [AnimatedImageState] holds every frame
+//! as its own [StatefulProtocol], and keeps track of which frame is due at which time.
+//!
+//! The state does not read the clock by itself, the application drives it with
+//! [AnimatedImageState::tick], and can use [AnimatedImageState::next_deadline] as the timeout of
+//! its event loop:
+//!
+//! ```rust,no_run
+//! # use std::time::Instant;
+//! # use image::{codecs::gif::GifDecoder, AnimationDecoder};
+//! # use ratatui::{backend::TestBackend, Terminal};
+//! # use ratatui_image::{animation::{AnimatedImage, AnimatedImageState}, picker::Picker};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let mut terminal = Terminal::new(TestBackend::new(80, 30))?;
+//! let picker = Picker::from_fontsize((7, 14));
+//! let file = std::io::BufReader::new(std::fs::File::open("animation.gif")?);
+//! let frames = GifDecoder::new(file)?.into_frames();
+//! let mut state = AnimatedImageState::new(&picker, frames)?;
+//! loop {
+//!     terminal.draw(|f| f.render_stateful_widget(AnimatedImage::default(), f.area(), &mut state))?;
+//!     // Wait for input events until `state.next_deadline()`...
+//!     state.tick(Instant::now());
+//! }
+//! # }
+//! ```
+//!
+//! With the Kitty protocol, prefer [crate::picker::Picker::new_animated_protocol], which lets the
+//! terminal play the animation by itself.
+
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, Frames};
+use ratatui::{buffer::Buffer, layout::Rect, widgets::StatefulWidget};
+
+use crate::{errors::Errors, picker::Picker, protocol::StatefulProtocol, Resize, Result};
+
+/// Frames with shorter delays are shown this long, like browsers do.
+const MIN_FRAME_DELAY: Duration = Duration::from_millis(10);
+
+/// Animated image widget that uses an [AnimatedImageState] state.
+///
+/// Like [crate::StatefulImage], it reacts to area resizes. All frames are resized and encoded at
+/// once when the area changes, so that the following frames render without delay.
+///
+/// ```rust
+/// # use ratatui::Frame;
+/// # use ratatui_image::{Resize, animation::{AnimatedImage, AnimatedImageState}};
+/// struct App {
+///     animation: AnimatedImageState,
+/// }
+/// fn ui(f: &mut Frame<'_>, app: &mut App) {
+///     let image = AnimatedImage::default().resize(Resize::Crop(None));
+///     f.render_stateful_widget(image, f.area(), &mut app.animation);
+/// }
+/// ```
+#[derive(Default)]
+pub struct AnimatedImage {
+    resize: Resize,
+}
+
+impl AnimatedImage {
+    pub const fn resize(self, resize: Resize) -> Self {
+        Self { resize }
+    }
+
+    pub const fn new() -> Self {
+        Self {
+            resize: Resize::Fit(None),
+        }
+    }
+}
+
+impl StatefulWidget for AnimatedImage {
+    type State = AnimatedImageState;
+    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+        if area.width == 0 || area.height == 0 {
+            return;
+        }
+
+        if state.frames[state.current]
+            .protocol
+            .needs_resize(&self.resize, area)
+            .is_some()
+        {
+            for frame in state.frames.iter_mut() {
+                if let Some(rect) = frame.protocol.needs_resize(&self.resize, area) {
+                    let background_color = frame.protocol.background_color();
+                    // The error is kept in the protocol of the frame, see
+                    // StatefulProtocol::last_error.
+                    let _ = frame
+                        .protocol
+                        .resize_encode(&self.resize, background_color, rect);
+                }
+            }
+        }
+        state.frames[state.current].protocol.render(area, buf);
+    }
+}
+
+struct AnimationFrame {
+    protocol: StatefulProtocol,
+    delay: Duration,
+}
+
+/// The state of an [AnimatedImage]: the frames and the playback position.
+pub struct AnimatedImageState {
+    frames: Vec<AnimationFrame>,
+    current: usize,
+    frame_started: Instant,
+    paused_at: Option<Instant>,
+    speed: f32,
+    loop_count: Option<u32>,
+    loops_done: u32,
+    finished: bool,
+}
+
+impl AnimatedImageState {
+    /// Decode all frames and create a [StatefulProtocol] for each one with the [Picker].
+    ///
+    /// Playback starts right away, and loops forever.
+    pub fn new(picker: &Picker, frames: Frames<'_>) -> Result<AnimatedImageState> {
+        let frames: Vec<AnimationFrame> = frames
+            .collect_frames()?
+            .into_iter()
+            .map(|frame| {
+                let delay = Duration::from(frame.delay());
+                let image: DynamicImage = frame.into_buffer().into();
+                AnimationFrame {
+                    protocol: picker.new_resize_protocol(image),
+                    delay,
+                }
+            })
+            .collect();
+        if frames.is_empty() {
+            return Err(Errors::NoFrames);
+        }
+        Ok(AnimatedImageState {
+            frames,
+            current: 0,
+            frame_started: Instant::now(),
+            paused_at: None,
+            speed: 1.0,
+            loop_count: None,
+            loops_done: 0,
+            finished: false,
+        })
+    }
+
+    /// Advance the animation to the frame that is due at `now`.
+    ///
+    /// Returns `true` if the current frame changed, i.e. the widget should be redrawn.
+    pub fn tick(&mut self, now: Instant) -> bool {
+        if self.paused_at.is_some() || self.finished {
+            return false;
+        }
+        let mut changed = false;
+        loop {
+            let due = self.frame_started + self.current_delay();
+            if now < due {
+                break;
+            }
+            self.frame_started = due;
+            if !self.advance() {
+                break;
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    /// The instant at which the next frame is due, or `None` if paused or finished.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.paused_at.is_some() || self.finished {
+            return None;
+        }
+        Some(self.frame_started + self.current_delay())
+    }
+
+    /// Pause playback, keeping the time left for the current frame.
+    pub fn pause(&mut self, now: Instant) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(now);
+        }
+    }
+
+    /// Resume playback after [AnimatedImageState::pause].
+    pub fn resume(&mut self, now: Instant) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.frame_started += now.saturating_duration_since(paused_at);
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// Whether the animation has played [AnimatedImageState::set_loop_count] times.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Jump to a frame, clamped to the last frame. This also restarts a finished animation.
+    pub fn seek(&mut self, frame: usize, now: Instant) {
+        self.current = frame.min(self.frames.len() - 1);
+        self.frame_started = now;
+        if self.paused_at.is_some() {
+            self.paused_at = Some(now);
+        }
+        if self.finished {
+            self.finished = false;
+            self.loops_done = 0;
+        }
+    }
+
+    /// Set the playback speed, where `1.0` is the speed the frame delays define.
+    ///
+    /// Values that are not finite or not greater than zero are ignored. Frames are never shown
+    /// shorter than 10ms, however fast the speed.
+    pub fn set_speed(&mut self, speed: f32) {
+        if speed.is_finite() && speed > 0.0 {
+            self.speed = speed;
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Set how many times the animation is played, `None` (the default) loops forever.
+    pub fn set_loop_count(&mut self, loop_count: Option<u32>) {
+        self.loop_count = loop_count;
+    }
+
+    /// The index of the frame that is currently shown.
+    pub fn current_frame(&self) -> usize {
+        self.current
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    fn current_delay(&self) -> Duration {
+        let delay = self.frames[self.current].delay.max(MIN_FRAME_DELAY);
+        // Round to whole nanoseconds, `Duration::div_f32` would be off by float imprecision.
+        Duration::from_nanos((delay.as_nanos() as f64 / self.speed as f64).round() as u64)
+            .max(MIN_FRAME_DELAY)
+    }
+
+    // Move to the next frame, or return false if the animation is over.
+    fn advance(&mut self) -> bool {
+        if self.current + 1 < self.frames.len() {
+            self.current += 1;
+            return true;
+        }
+        self.loops_done = self.loops_done.saturating_add(1);
+        if self
+            .loop_count
+            .is_some_and(|loop_count| self.loops_done >= loop_count)
+        {
+            self.finished = true;
+            return false;
+        }
+        self.current = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use image::{Delay, Frame, Frames, ImageBuffer, Rgba};
+
+    use super::{AnimatedImageState, MIN_FRAME_DELAY};
+    use crate::picker::Picker;
+
+    fn state(delays: &[u32]) -> AnimatedImageState {
+        let frames: Vec<Frame> = delays
+            .iter()
+            .map(|ms| {
+                Frame::from_parts(
+                    ImageBuffer::from_pixel(4, 4, Rgba([255, 0, 0, 255])),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(*ms, 1),
+                )
+            })
+            .collect();
+        let frames = Frames::new(Box::new(frames.into_iter().map(Ok)));
+        AnimatedImageState::new(&Picker::from_fontsize((8, 16)), frames).unwrap()
+    }
+
+    #[test]
+    fn test_tick_loops() {
+        let mut state = state(&[100, 200]);
+        let start = state.frame_started;
+        assert!(!state.tick(start + Duration::from_millis(99)));
+        assert_eq!(state.current_frame(), 0);
+        assert!(state.tick(start + Duration::from_millis(100)));
+        assert_eq!(state.current_frame(), 1);
+        assert_eq!(
+            state.next_deadline(),
+            Some(start + Duration::from_millis(300))
+        );
+        assert!(state.tick(start + Duration::from_millis(350)));
+        assert_eq!(state.current_frame(), 0);
+    }
+
+    #[test]
+    fn test_loop_count_pause_speed() {
+        let mut state = state(&[100, 100]);
+        let start = state.frame_started;
+        state.set_loop_count(Some(1));
+        state.set_speed(2.0);
+        state.pause(start);
+        assert!(!state.tick(start + Duration::from_secs(1)));
+        assert_eq!(state.next_deadline(), None);
+        state.resume(start + Duration::from_secs(1));
+        assert!(state.tick(start + Duration::from_millis(1050)));
+        assert_eq!(state.current_frame(), 1);
+        assert!(!state.tick(start + Duration::from_secs(2)));
+        assert!(state.is_finished());
+        state.seek(0, Instant::now());
+        assert!(!state.is_finished());
+    }
+
+    #[test]
+    fn test_extreme_speed() {
+        let mut state = state(&[100, 100]);
+        let start = state.frame_started;
+        state.set_speed(f32::INFINITY);
+        assert_eq!(state.speed(), 1.0);
+        state.set_speed(f32::MAX);
+        assert_eq!(state.next_deadline(), Some(start + MIN_FRAME_DELAY));
+        assert!(state.tick(start + Duration::from_millis(35)));
+        assert_eq!(state.current_frame(), 1);
+    }
+}
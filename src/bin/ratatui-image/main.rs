@@ -1,5 +1,6 @@
 use std::{
-    env, io,
+    env,
+    io::{self, Write},
     time::{Duration, Instant},
 };
 
@@ -16,7 +17,11 @@ use ratatui::{
     widgets::{Block, Borders, Paragraph, Wrap},
     Frame, Terminal,
 };
-use ratatui_image::{picker::Picker, protocol::StatefulProtocol, StatefulImage};
+use ratatui_image::{
+    picker::Picker,
+    protocol::{kitty, StatefulProtocol},
+    StatefulImage,
+};
 
 struct App {
     pub filename: String,
@@ -65,6 +70,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let tick_rate = Duration::from_millis(1000);
     loop {
         terminal.draw(|f| ui(f, &mut app))?;
+        // Kitty images are transmitted after the frame, outside of the buffer.
+        if let Some(seq) = kitty::take_pending_sequences() {
+            terminal.backend_mut().write_all(seq.as_bytes())?;
+            terminal.backend_mut().flush()?;
+        }
 
         let timeout = tick_rate
             .checked_sub(last_tick.elapsed())
@@ -8,6 +8,8 @@ pub enum Errors {
     NoStdinResponse,
     #[error("Sixel error: {0}")]
     Sixel(String),
+    #[error("Animation has no frames")]
+    NoFrames,
     #[error("Tmux error: {0}")]
     Tmux(&'static str),
     #[error("IO error: {0}")]
@@ -30,7 +30,8 @@
 //! # Quick start
 //! ```rust
 //! use ratatui::{backend::TestBackend, Terminal, Frame};
-//! use ratatui_image::{picker::Picker, StatefulImage, protocol::StatefulProtocol};
+//! use ratatui_image::{picker::Picker, StatefulImage, protocol::{kitty, StatefulProtocol}};
+//! use std::io::Write;
 //!
 //! struct App {
 //!     // We need to hold the render state.
@@ -55,6 +56,10 @@
 //!
 //!     // This would be your typical `loop {` in a real app:
 //!     terminal.draw(|f| ui(f, &mut app))?;
+//!     // Kitty images are transmitted after the frame, outside of the buffer.
+//!     if let Some(seq) = kitty::take_pending_sequences() {
+//!         std::io::stdout().write_all(seq.as_bytes())?;
+//!     }
 //!
 //!     Ok(())
 //! }
@@ -82,6 +87,9 @@
 //!   The resizing and encoding is blocking by default, but it is possible to offload this to another
 //!   thread or async task (see `examples/async.rs`). It must be rendered with
 //!   [`render_stateful_widget`] (i.e. with some mutable state).
+//! * The [animation::AnimatedImage] widget plays animated images (GIF, APNG, WebP) with any
+//!   protocol, by rendering the frames one after another. Its state is driven by the app's event
+//!   loop with a `tick` and a `next_deadline`.
 //!
 //! # Examples
 //!
@@ -111,9 +119,11 @@ use protocol::{ImageSource, Protocol, StatefulProtocol};
 use ratatui::{
     buffer::Buffer,
     layout::Rect,
-    widgets::{StatefulWidget, Widget},
+    style::{Color, Style},
+    widgets::{Paragraph, StatefulWidget, Widget, Wrap},
 };
 
+pub mod animation;
 pub mod errors;
 pub mod picker;
 pub mod protocol;
@@ -143,11 +153,23 @@ pub type FontSize = (u16, u16);
 /// ```
 pub struct Image<'a> {
     image: &'a mut Protocol,
+    z_index: i32,
 }
 
 impl<'a> Image<'a> {
     pub fn new(image: &'a mut Protocol) -> Image<'a> {
-        Image { image }
+        Image { image, z_index: 0 }
+    }
+
+    /// Stack the image relative to text and other images, defaults to `0`.
+    ///
+    /// Negative values draw the image under text, for watermarks or backgrounds. Values below
+    /// `-1_073_741_824` also draw it under cells with a non-default background color. Images with
+    /// a higher z-index are drawn over those with a lower one.
+    ///
+    /// Only the Kitty protocol supports this, for all others it has no effect.
+    pub fn z_index(self, z_index: i32) -> Image<'a> {
+        Image { z_index, ..self }
     }
 }
 
@@ -157,6 +179,7 @@ impl Widget for Image<'_> {
             return;
         }
 
+        self.image.set_z_index(self.z_index);
         self.image.render(area, buf);
     }
 }
@@ -183,16 +206,34 @@ impl Widget for Image<'_> {
 #[derive(Default)]
 pub struct StatefulImage {
     resize: Resize,
+    z_index: i32,
+    error_placeholder: bool,
 }
 
 impl StatefulImage {
     pub const fn resize(self, resize: Resize) -> Self {
-        Self { resize }
+        Self { resize, ..self }
+    }
+
+    /// Stack the image relative to text and other images, see [Image::z_index].
+    pub const fn z_index(self, z_index: i32) -> Self {
+        Self { z_index, ..self }
+    }
+
+    /// Show the error message in the area if the image could not be encoded, instead of leaving
+    /// it empty. See [StatefulProtocol::last_error].
+    pub const fn error_placeholder(self, error_placeholder: bool) -> Self {
+        Self {
+            error_placeholder,
+            ..self
+        }
     }
 
     pub const fn new() -> Self {
         Self {
             resize: Resize::Fit(None),
+            z_index: 0,
+            error_placeholder: false,
         }
     }
 }
@@ -204,7 +245,15 @@ impl StatefulWidget for StatefulImage {
             return;
         }
 
+        state.set_z_index(self.z_index);
         state.resize_encode_render(&self.resize, state.background_color(), area, buf);
+
+        if let Some(err) = state.last_error().filter(|_| self.error_placeholder) {
+            Paragraph::new(format!("Image error: {err}"))
+                .style(Style::new().fg(Color::Red))
+                .wrap(Wrap { trim: true })
+                .render(area, buf);
+        }
     }
 }
 
@@ -257,20 +306,33 @@ impl Resize {
         font_size: FontSize,
         area: Rect,
         background_color: Rgba<u8>,
+    ) -> DynamicImage {
+        self.resize_frame(&source.image, font_size, area, background_color)
+    }
+
+    /// Resize any image, such as a frame of an animated [`ImageSource`], to fit the `area`.
+    fn resize_frame(
+        &self,
+        image: &DynamicImage,
+        font_size: FontSize,
+        area: Rect,
+        background_color: Rgba<u8>,
     ) -> DynamicImage {
         let width = (area.width * font_size.0) as u32;
         let height = (area.height * font_size.1) as u32;
 
         // Resize/Crop/etc., fitting a multiple of font-size, but not necessarily the area.
-        let mut image = self.resize_image(source, width, height);
-
-        // Always pad to area size with background color, Sixel doesn't have transparency
-        // and would get a white background by the sixel library.
-        // Once Sixel gets transparency support, only pad
-        // `if image.width() != width || image.height() != height`.
-        let mut bg: DynamicImage = ImageBuffer::from_pixel(width, height, background_color).into();
-        imageops::overlay(&mut bg, &image, 0, 0);
-        image = bg;
+        let mut image = self.resize_image(image, width, height);
+
+        // Pad to area size with background color, if the image does not cover the area. The
+        // graphics protocols leave transparent padding undrawn, but halfblocks and the text
+        // protocols draw the color of the padding.
+        if image.width() != width || image.height() != height {
+            let mut bg: DynamicImage =
+                ImageBuffer::from_pixel(width, height, background_color).into();
+            imageops::overlay(&mut bg, &image, 0, 0);
+            image = bg;
+        }
         image
     }
 
@@ -317,34 +379,69 @@ impl Resize {
         None
     }
 
-    fn resize_image(&self, source: &ImageSource, width: u32, height: u32) -> DynamicImage {
+    fn resize_image(&self, image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
         const DEFAULT_FILTER_TYPE: FilterType = FilterType::Nearest;
-        const DEFAULT_CROP_OPTIONS: CropOptions = CropOptions {
-            clip_top: false,
-            clip_left: false,
-        };
-        let image = &source.image;
         match self {
             Self::Fit(filter_type) | Self::Scale(filter_type) => {
                 image.resize(width, height, filter_type.unwrap_or(DEFAULT_FILTER_TYPE))
             }
             Self::Crop(options) => {
-                let options = options.as_ref().unwrap_or(&DEFAULT_CROP_OPTIONS);
-                let y = if options.clip_top {
-                    image.height().saturating_sub(height)
-                } else {
-                    0
-                };
-                let x = if options.clip_left {
-                    image.width().saturating_sub(width)
-                } else {
-                    0
-                };
+                let (x, y) = Self::crop_offset(options, image, width, height);
                 image.crop_imm(x, y, width, height)
             }
         }
     }
 
+    /// The part of the image that is shown in `area`, as `(x, y, width, height)` in pixels.
+    ///
+    /// This is for protocols that can scale and crop on the terminal side, instead of
+    /// [Resize::resize].
+    fn source_rect(
+        &self,
+        image: &DynamicImage,
+        font_size: FontSize,
+        area: Rect,
+    ) -> (u32, u32, u32, u32) {
+        match self {
+            Self::Fit(_) | Self::Scale(_) => (0, 0, image.width(), image.height()),
+            Self::Crop(options) => {
+                let width = (area.width as u32) * (font_size.0 as u32);
+                let height = (area.height as u32) * (font_size.1 as u32);
+                let (x, y) = Self::crop_offset(options, image, width, height);
+                (
+                    x,
+                    y,
+                    min(width, image.width() - x),
+                    min(height, image.height() - y),
+                )
+            }
+        }
+    }
+
+    fn crop_offset(
+        options: &Option<CropOptions>,
+        image: &DynamicImage,
+        width: u32,
+        height: u32,
+    ) -> (u32, u32) {
+        const DEFAULT_CROP_OPTIONS: CropOptions = CropOptions {
+            clip_top: false,
+            clip_left: false,
+        };
+        let options = options.as_ref().unwrap_or(&DEFAULT_CROP_OPTIONS);
+        let y = if options.clip_top {
+            image.height().saturating_sub(height)
+        } else {
+            0
+        };
+        let x = if options.clip_left {
+            image.width().saturating_sub(width)
+        } else {
+            0
+        };
+        (x, y)
+    }
+
     fn needs_resize_pixels(&self, image: &DynamicImage, width: u32, height: u32) -> (u32, u32) {
         match self {
             Self::Fit(_) => fit_area_proportionally(
@@ -454,4 +551,28 @@ mod tests {
         let to = resize.needs_resize(&s(100, 100), FONT_SIZE, r(10, 10), r(10, 8), false);
         assert_eq!(Some(r(10, 8)), to);
     }
+    #[test]
+    fn source_rect_crop() {
+        let image = s(100, 100).image;
+        let resize = Resize::Crop(None);
+        assert_eq!(
+            (0, 0, 50, 100),
+            resize.source_rect(&image, FONT_SIZE, r(5, 12))
+        );
+
+        let resize = Resize::Crop(Some(CropOptions {
+            clip_top: true,
+            clip_left: true,
+        }));
+        assert_eq!(
+            (50, 20, 50, 80),
+            resize.source_rect(&image, FONT_SIZE, r(5, 8))
+        );
+
+        let resize = Resize::Fit(None);
+        assert_eq!(
+            (0, 0, 100, 100),
+            resize.source_rect(&image, FONT_SIZE, r(5, 8))
+        );
+    }
 }
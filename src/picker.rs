@@ -2,29 +2,39 @@
 
 use std::{
     env,
-    io::{self, Read, Write},
-    time::Duration,
+    io::{self, ErrorKind, Read, Write},
+    thread,
+    time::{Duration, Instant},
 };
 
 use cap_parser::{Capability, Parser};
-use image::{DynamicImage, Rgba};
+use image::{DynamicImage, Frames, Rgba};
 use ratatui::layout::Rect;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
+use terminal::TerminalInfo;
 
 use crate::{
     errors::Errors,
     protocol::{
+        ascii::{Ascii, AsciiOptions, StatefulAscii},
+        blocks::{BlockKind, Blocks, StatefulBlocks},
+        braille::{Braille, BrailleOptions, StatefulBraille},
+        color::ColorDepth,
         halfblocks::{Halfblocks, StatefulHalfblocks},
-        iterm2::{Iterm2, StatefulIterm2},
-        kitty::{Kitty, StatefulKitty},
-        sixel::{Sixel, StatefulSixel},
+        iterm2::{Iterm2, Iterm2Options, StatefulIterm2},
+        kitty::{
+            self, Kitty, KittyOptions, KittyPlacement, MediumFile, StatefulKitty,
+            TransmissionMedium,
+        },
+        sixel::{Sixel, SixelOptions, StatefulSixel},
         Protocol, StatefulProtocol,
     },
     FontSize, ImageSource, Resize, Result,
 };
 
 pub mod cap_parser;
+pub mod terminal;
 
 const DEFAULT_BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 0]);
 
@@ -32,8 +42,18 @@ const DEFAULT_BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 0]);
 pub struct Picker {
     font_size: FontSize,
     protocol_type: ProtocolType,
-    background_color: Rgba<u8>,
+    /// Set explicitly, otherwise see [Picker::background_color].
+    background_color: Option<Rgba<u8>>,
     is_tmux: bool,
+    terminal_info: TerminalInfo,
+    sixel_options: SixelOptions,
+    sixel_colors: Option<u16>,
+    sixel_max_size: Option<(u16, u16)>,
+    kitty_options: KittyOptions,
+    iterm2_options: Iterm2Options,
+    braille_options: BrailleOptions,
+    ascii_options: AsciiOptions,
+    color_depth: ColorDepth,
 }
 
 /// Serde-friendly protocol-type enum for [Picker].
@@ -48,6 +68,16 @@ pub enum ProtocolType {
     Sixel,
     Kitty,
     Iterm2,
+    /// Unicode quadrant characters, 2x2 pixels per cell.
+    Quadrants,
+    /// Unicode 13 sextant characters, 2x3 pixels per cell.
+    Sextants,
+    /// Unicode 16 octant characters, 2x4 pixels per cell.
+    Octants,
+    /// Unicode braille patterns, 2x4 dots per cell, one color per cell.
+    Braille,
+    /// Plain text characters from a luminance ramp, optionally colored.
+    Ascii,
 }
 
 impl ProtocolType {
@@ -56,7 +86,26 @@ impl ProtocolType {
             ProtocolType::Halfblocks => ProtocolType::Sixel,
             ProtocolType::Sixel => ProtocolType::Kitty,
             ProtocolType::Kitty => ProtocolType::Iterm2,
-            ProtocolType::Iterm2 => ProtocolType::Halfblocks,
+            ProtocolType::Iterm2 => ProtocolType::Quadrants,
+            ProtocolType::Quadrants => ProtocolType::Sextants,
+            ProtocolType::Sextants => ProtocolType::Octants,
+            ProtocolType::Octants => ProtocolType::Braille,
+            ProtocolType::Braille => ProtocolType::Ascii,
+            ProtocolType::Ascii => ProtocolType::Halfblocks,
+        }
+    }
+
+    /// Whether the protocol renders with text characters and colors, instead of some terminal
+    /// graphics protocol.
+    pub fn is_text(&self) -> bool {
+        match self {
+            ProtocolType::Halfblocks
+            | ProtocolType::Quadrants
+            | ProtocolType::Sextants
+            | ProtocolType::Octants
+            | ProtocolType::Braille
+            | ProtocolType::Ascii => true,
+            ProtocolType::Sixel | ProtocolType::Kitty | ProtocolType::Iterm2 => false,
         }
     }
 }
@@ -74,43 +123,69 @@ impl Picker {
     /// let mut picker = Picker::from_query_stdio();
     /// ```
     ///
+    /// Input that arrives after the response, for example keypresses, is discarded. See
+    /// [Picker::from_query_stdio_with_input] to keep it.
     pub fn from_query_stdio() -> Result<Picker> {
+        Picker::from_query_stdio_with_input().map(|(picker, _input)| picker)
+    }
+
+    /// Like [Picker::from_query_stdio], but also returns the bytes that were read after the
+    /// response, which are regular input that the application should handle.
+    ///
+    /// # Example
+    /// ```rust
+    /// use ratatui_image::picker::Picker;
+    /// if let Ok((picker, input)) = Picker::from_query_stdio_with_input() {
+    ///     // Feed `input` to the event handling.
+    /// }
+    /// ```
+    pub fn from_query_stdio_with_input() -> Result<(Picker, Vec<u8>)> {
         // Detect tmux, and only if positive then take some risky guess for iTerm2 support.
-        let (is_tmux, tmux_proto) = detect_tmux_and_outer_protocol_from_env();
+        let env = EnvHints::from_env();
 
         // Write and read to stdin to query protocol capabilities and font-size.
-        match query_with_timeout(is_tmux, Duration::from_secs(1)) {
-            Ok((capability_proto, font_size)) => {
-                // If some env var says that we should try iTerm2, then disregard protocol-from-capabilities.
-                let iterm2_proto = iterm2_from_env();
+        let (result, input) = match query_with_timeout(env.is_tmux, Duration::from_secs(1)) {
+            Ok((result, input)) => (Ok(result), input),
+            Err(err) => (Err(err), vec![]),
+        };
+        Ok((Picker::from_query_result(result, env)?, input))
+    }
 
-                let protocol_type = tmux_proto
-                    .or(iterm2_proto)
-                    .or(capability_proto)
-                    .unwrap_or(ProtocolType::Halfblocks);
+    /// Query a terminal that is not the process stdio for graphics capabilities and font-size,
+    /// for example the PTY of an SSH server, or a web terminal bridge.
+    ///
+    /// Writes the query to `writer`, and reads the response from `reader` until the terminal has
+    /// answered, or `timeout` has passed. Then whatever arrived so far is used, see
+    /// [PickerQuery::into_picker]. Nothing is guessed from environment variables, since those
+    /// belong to this process and not to the terminal. For an event loop that can't block, see
+    /// [PickerQuery].
+    ///
+    /// The timeout is only checked between reads, so a blocking `reader` should have a read
+    /// timeout or be non-blocking, in case the terminal never answers. Returns the picker, and the
+    /// bytes that were read after the response, which are regular input.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use std::{net::TcpStream, time::Duration};
+    /// use ratatui_image::picker::Picker;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let stream = TcpStream::connect("127.0.0.1:2323")?;
+    /// stream.set_read_timeout(Some(Duration::from_millis(50)))?;
+    /// let (picker, input) = Picker::from_query(&stream, &stream, Duration::from_secs(1))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_query<R: Read, W: Write>(
+        mut reader: R,
+        mut writer: W,
+        timeout: Duration,
+    ) -> Result<(Picker, Vec<u8>)> {
+        let mut query = PickerQuery::new();
+        writer.write_all(query.query().as_bytes())?;
+        writer.flush()?;
 
-                if let Some(font_size) = font_size {
-                    Ok(Picker {
-                        font_size,
-                        background_color: DEFAULT_BACKGROUND,
-                        protocol_type,
-                        is_tmux,
-                    })
-                } else {
-                    Err(Errors::NoFontSize)
-                }
-            }
-            Err(Errors::NoCap) => Ok(Picker {
-                // This is completely arbitrary. For halfblocks, it doesn't have to be precise
-                // since we're not rendering pixels. It should be roughly 1:2 ratio, and some
-                // reasonable size.
-                font_size: (10, 20),
-                background_color: DEFAULT_BACKGROUND,
-                protocol_type: ProtocolType::Halfblocks,
-                is_tmux,
-            }),
-            Err(err) => Err(err),
-        }
+        let input = read_response(&mut reader, &mut query, Some(Instant::now() + timeout))?;
+        Ok((query.into_picker()?, input))
     }
 
     /// Create a picker from a given terminal [FontSize].
@@ -137,13 +212,22 @@ impl Picker {
 
         Picker {
             font_size,
-            background_color: DEFAULT_BACKGROUND,
+            background_color: None,
             protocol_type,
             is_tmux,
+            terminal_info: TerminalInfo::default(),
+            sixel_options: SixelOptions::default(),
+            sixel_colors: None,
+            sixel_max_size: None,
+            kitty_options: KittyOptions::default(),
+            iterm2_options: iterm2_options_from_env(is_tmux),
+            braille_options: BrailleOptions::default(),
+            ascii_options: AsciiOptions::default(),
+            color_depth: color_depth_from_env(),
         }
     }
 
-    pub fn protocol_type(self) -> ProtocolType {
+    pub fn protocol_type(&self) -> ProtocolType {
         self.protocol_type
     }
 
@@ -151,13 +235,138 @@ impl Picker {
         self.protocol_type = protocol_type;
     }
 
-    pub fn font_size(self) -> FontSize {
+    pub fn font_size(&self) -> FontSize {
         self.font_size
     }
 
-    // Change the default background color (transparent black).
+    /// The terminal name, version and default colors, if it answered when queried.
+    pub fn terminal_info(&self) -> TerminalInfo {
+        self.terminal_info
+    }
+
+    /// Whether the terminal has a dark background, if it reported its default colors when
+    /// queried. See [TerminalInfo::is_dark].
+    pub fn is_dark(&self) -> Option<bool> {
+        self.terminal_info.is_dark()
+    }
+
+    pub fn color_depth(&self) -> ColorDepth {
+        self.color_depth
+    }
+
+    /// Override the color depth that was guessed from the environment, for Halfblocks.
+    ///
+    /// Only affects protocols that are created afterwards.
+    pub fn set_color_depth(&mut self, color_depth: ColorDepth) {
+        self.color_depth = color_depth;
+    }
+
+    pub fn sixel_options(&self) -> SixelOptions {
+        self.sixel_options
+    }
+
+    /// Change the quantization and dithering of the Sixel protocol.
+    ///
+    /// Only affects protocols that are created afterwards.
+    pub fn set_sixel_options(&mut self, sixel_options: SixelOptions) {
+        self.sixel_options = sixel_options;
+    }
+
+    /// The number of sixel color registers that the terminal reported, if queried.
+    ///
+    /// The palette of the Sixel protocol is capped at this size.
+    pub fn sixel_colors(&self) -> Option<u16> {
+        self.sixel_colors
+    }
+
+    /// The maximum sixel image size in pixels that the terminal reported, if queried.
+    ///
+    /// Larger images are scaled down by the Sixel protocol, see [SixelOptions::max_size].
+    pub fn sixel_max_size(&self) -> Option<(u16, u16)> {
+        self.sixel_max_size
+    }
+
+    /// The [SixelOptions], capped by what the terminal reported.
+    fn capped_sixel_options(&self) -> SixelOptions {
+        let mut options = self.sixel_options;
+        if let Some(colors) = self.sixel_colors {
+            options.colors = options.colors.min(colors);
+        }
+        options.max_size = match (options.max_size, self.sixel_max_size) {
+            (Some((w, h)), Some((max_w, max_h))) => Some((w.min(max_w), h.min(max_h))),
+            (max_size, terminal_max_size) => max_size.or(terminal_max_size),
+        };
+        options
+    }
+
+    pub fn kitty_options(&self) -> KittyOptions {
+        self.kitty_options
+    }
+
+    /// Change how the Kitty protocol places images, see [KittyOptions].
+    ///
+    /// Only affects protocols that are created afterwards.
+    pub fn set_kitty_options(&mut self, kitty_options: KittyOptions) {
+        self.kitty_options = kitty_options;
+    }
+
+    pub fn iterm2_options(&self) -> Iterm2Options {
+        self.iterm2_options
+    }
+
+    /// Change how the ITerm2 protocol transmits images.
+    ///
+    /// Only affects protocols that are created afterwards.
+    pub fn set_iterm2_options(&mut self, iterm2_options: Iterm2Options) {
+        self.iterm2_options = iterm2_options;
+    }
+
+    pub fn braille_options(&self) -> BrailleOptions {
+        self.braille_options
+    }
+
+    /// Change the thresholding, dithering and coloring of the Braille protocol.
+    ///
+    /// Only affects protocols that are created afterwards.
+    pub fn set_braille_options(&mut self, braille_options: BrailleOptions) {
+        self.braille_options = braille_options;
+    }
+
+    pub fn ascii_options(&self) -> AsciiOptions {
+        self.ascii_options
+    }
+
+    /// Change the character ramp, edges and coloring of the Ascii protocol.
+    ///
+    /// Only affects protocols that are created afterwards.
+    pub fn set_ascii_options(&mut self, ascii_options: AsciiOptions) {
+        self.ascii_options = ascii_options;
+    }
+
+    /// Change the background color, for padding and transparent pixels. Defaults to the terminal
+    /// background for sixel and the text protocols when queried, otherwise transparent black.
+    ///
+    /// Only affects protocols that are created afterwards.
     pub fn set_background_color<T: Into<Rgba<u8>>>(&mut self, background_color: T) {
-        self.background_color = background_color.into();
+        self.background_color = Some(background_color.into());
+    }
+
+    /// The background color for new protocols. Sixel and the text protocols can't draw
+    /// transparent pixels, so unless set explicitly they pad and composite with the terminal
+    /// background, if it was queried. Kitty and iTerm2 keep the padding transparent.
+    fn background_color(&self) -> Rgba<u8> {
+        match (self.background_color, self.terminal_info.background) {
+            (Some(background_color), _) => background_color,
+            (None, Some([r, g, b]))
+                if !matches!(
+                    self.protocol_type,
+                    ProtocolType::Kitty | ProtocolType::Iterm2
+                ) =>
+            {
+                Rgba([r, g, b, 255])
+            }
+            _ => DEFAULT_BACKGROUND,
+        }
     }
 
     /// Returns a new protocol for [`crate::Image`] widgets that fits into the given size.
@@ -167,7 +376,7 @@ impl Picker {
         size: Rect,
         resize: Resize,
     ) -> Result<Protocol> {
-        let source = ImageSource::new(image, self.font_size, self.background_color);
+        let source = ImageSource::new(image, self.font_size, self.background_color());
 
         let (image, area) =
             match resize.needs_resize(&source, self.font_size, source.desired, size, false) {
@@ -175,49 +384,236 @@ impl Picker {
                     // Not exactly sure why this is necessary only for Protocol and not
                     // StatefulProtocol, but the image proportion comes out wrong if we don't
                     // divide height by half here.
-                    let font_size = if self.protocol_type == ProtocolType::Halfblocks {
+                    let font_size = if self.protocol_type.is_text() {
                         (self.font_size.0, self.font_size.1 / 2)
                     } else {
                         self.font_size
                     };
-                    let image = resize.resize(&source, font_size, size, self.background_color);
+                    let image = resize.resize(&source, font_size, size, self.background_color());
                     (image, area)
                 }
                 None => (source.image, source.desired),
             };
 
         match self.protocol_type {
-            ProtocolType::Halfblocks => Ok(Protocol::Halfblocks(Halfblocks::new(image, area)?)),
-            ProtocolType::Sixel => Ok(Protocol::Sixel(Sixel::new(image, area, self.is_tmux)?)),
+            ProtocolType::Halfblocks => Ok(Protocol::Halfblocks(Halfblocks::new(
+                image,
+                area,
+                self.color_depth,
+            )?)),
+            ProtocolType::Sixel => Ok(Protocol::Sixel(Sixel::new(
+                image,
+                area,
+                self.is_tmux,
+                self.capped_sixel_options(),
+            )?)),
             ProtocolType::Kitty => Ok(Protocol::Kitty(Kitty::new(
                 image,
                 area,
-                rand::random(),
+                kitty::new_image_id(),
                 self.is_tmux,
+                self.kitty_options,
+            )?)),
+            ProtocolType::Iterm2 => Ok(Protocol::ITerm2(Iterm2::new(
+                image,
+                area,
+                self.is_tmux,
+                self.iterm2_options,
+            )?)),
+            ProtocolType::Quadrants => Ok(Protocol::Blocks(Blocks::new(
+                image,
+                area,
+                BlockKind::Quadrants,
+            )?)),
+            ProtocolType::Sextants => Ok(Protocol::Blocks(Blocks::new(
+                image,
+                area,
+                BlockKind::Sextants,
+            )?)),
+            ProtocolType::Octants => Ok(Protocol::Blocks(Blocks::new(
+                image,
+                area,
+                BlockKind::Octants,
+            )?)),
+            ProtocolType::Braille => Ok(Protocol::Braille(Braille::new(
+                image,
+                area,
+                self.braille_options,
+            )?)),
+            ProtocolType::Ascii => Ok(Protocol::Ascii(Ascii::new(
+                image,
+                area,
+                self.ascii_options,
             )?)),
-            ProtocolType::Iterm2 => Ok(Protocol::ITerm2(Iterm2::new(image, area, self.is_tmux)?)),
         }
     }
 
     /// Returns a new *stateful* protocol for [`crate::StatefulImage`] widgets.
     pub fn new_resize_protocol(&self, image: DynamicImage) -> StatefulProtocol {
-        let source = ImageSource::new(image, self.font_size, self.background_color);
+        let source = ImageSource::new(image, self.font_size, self.background_color());
+        self.new_stateful_protocol(source)
+    }
+
+    /// Returns a new *stateful* protocol for [`crate::StatefulImage`] widgets, from the frames of
+    /// an animated image (GIF, APNG, WebP).
+    ///
+    /// With the Kitty protocol, all frames are transmitted and the terminal plays the animation by
+    /// itself. Other protocols only show the first frame.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use image::{codecs::gif::GifDecoder, AnimationDecoder};
+    /// use ratatui_image::picker::Picker;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let picker = Picker::from_fontsize((7, 14));
+    /// let file = std::io::BufReader::new(std::fs::File::open("animation.gif")?);
+    /// let frames = GifDecoder::new(file)?.into_frames();
+    /// let image = picker.new_animated_protocol(frames)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new_animated_protocol(&self, frames: Frames<'_>) -> Result<StatefulProtocol> {
+        let source = ImageSource::new_animated(frames, self.font_size, self.background_color())?;
+        Ok(self.new_stateful_protocol(source))
+    }
+
+    /// Returns a new *stateful* protocol for [`crate::StatefulImage`] widgets, from an encoded
+    /// image file (PNG, JPEG, ...).
+    ///
+    /// Like [Picker::new_resize_protocol], but the file is kept so that the ITerm2 protocol can
+    /// send it as it is, see [crate::protocol::iterm2::Iterm2Encoding::Original].
+    pub fn new_encoded_protocol(&self, bytes: Vec<u8>) -> Result<StatefulProtocol> {
+        let source = ImageSource::new_encoded(bytes, self.font_size, self.background_color())?;
+        Ok(self.new_stateful_protocol(source))
+    }
+
+    fn new_stateful_protocol(&self, source: ImageSource) -> StatefulProtocol {
         match self.protocol_type {
-            ProtocolType::Halfblocks => {
-                StatefulProtocol::Halfblocks(StatefulHalfblocks::new(source, self.font_size))
-            }
-            ProtocolType::Sixel => {
-                StatefulProtocol::Sixel(StatefulSixel::new(source, self.font_size, self.is_tmux))
-            }
+            ProtocolType::Halfblocks => StatefulProtocol::Halfblocks(StatefulHalfblocks::new(
+                source,
+                self.font_size,
+                self.color_depth,
+            )),
+            ProtocolType::Sixel => StatefulProtocol::Sixel(StatefulSixel::new(
+                source,
+                self.font_size,
+                self.is_tmux,
+                self.capped_sixel_options(),
+            )),
             ProtocolType::Kitty => StatefulProtocol::Kitty(StatefulKitty::new(
                 source,
                 self.font_size,
-                rand::random(),
+                kitty::new_image_id(),
+                self.is_tmux,
+                self.kitty_options,
+            )),
+            ProtocolType::Iterm2 => StatefulProtocol::ITerm2(StatefulIterm2::new(
+                source,
+                self.font_size,
                 self.is_tmux,
+                self.iterm2_options,
+            )),
+            ProtocolType::Quadrants => StatefulProtocol::Blocks(StatefulBlocks::new(
+                source,
+                self.font_size,
+                BlockKind::Quadrants,
+            )),
+            ProtocolType::Sextants => StatefulProtocol::Blocks(StatefulBlocks::new(
+                source,
+                self.font_size,
+                BlockKind::Sextants,
+            )),
+            ProtocolType::Octants => StatefulProtocol::Blocks(StatefulBlocks::new(
+                source,
+                self.font_size,
+                BlockKind::Octants,
+            )),
+            ProtocolType::Braille => StatefulProtocol::Braille(StatefulBraille::new(
+                source,
+                self.font_size,
+                self.braille_options,
+            )),
+            ProtocolType::Ascii => StatefulProtocol::Ascii(StatefulAscii::new(
+                source,
+                self.font_size,
+                self.ascii_options,
             )),
-            ProtocolType::Iterm2 => {
-                StatefulProtocol::ITerm2(StatefulIterm2::new(source, self.font_size, self.is_tmux))
+        }
+    }
+
+    /// Build a picker from what the query found out, or a halfblocks picker if the terminal did
+    /// not answer any of it.
+    fn from_query_result(result: Result<QueryResult>, env: EnvHints) -> Result<Picker> {
+        let is_tmux = env.is_tmux;
+        match result {
+            Ok(QueryResult {
+                protocol_type: capability_proto,
+                font_size,
+                kitty_medium,
+                sixel_colors,
+                sixel_max_size,
+                terminal_info,
+            }) => {
+                // A terminal with an entry in the quirks table uses that. Otherwise, if some env
+                // var says that we should try iTerm2, then disregard protocol-from-capabilities.
+                let quirks = terminal_info.quirks();
+                let env_proto = if quirks.is_none() {
+                    env.protocol_type
+                } else {
+                    None
+                };
+                let quirks = quirks.unwrap_or_default();
+
+                let protocol_type = quirks
+                    .protocol
+                    .or(env_proto)
+                    .or(capability_proto)
+                    .unwrap_or(ProtocolType::Halfblocks);
+
+                let kitty_options = kitty_options_from_quirks(is_tmux, quirks.kitty_direct);
+
+                if let Some(font_size) = font_size {
+                    Ok(Picker {
+                        font_size,
+                        background_color: None,
+                        protocol_type,
+                        is_tmux,
+                        terminal_info,
+                        sixel_options: SixelOptions::default(),
+                        sixel_colors,
+                        sixel_max_size,
+                        kitty_options: KittyOptions {
+                            medium: kitty_medium,
+                            ..kitty_options
+                        },
+                        iterm2_options: iterm2_options_from_env(is_tmux),
+                        braille_options: BrailleOptions::default(),
+                        ascii_options: AsciiOptions::default(),
+                        color_depth: env.color_depth,
+                    })
+                } else {
+                    Err(Errors::NoFontSize)
+                }
             }
+            Err(Errors::NoCap) => Ok(Picker {
+                // This is completely arbitrary. For halfblocks, it doesn't have to be precise
+                // since we're not rendering pixels. It should be roughly 1:2 ratio, and some
+                // reasonable size.
+                font_size: (10, 20),
+                background_color: None,
+                protocol_type: ProtocolType::Halfblocks,
+                is_tmux,
+                terminal_info: TerminalInfo::default(),
+                sixel_options: SixelOptions::default(),
+                sixel_colors: None,
+                sixel_max_size: None,
+                kitty_options: KittyOptions::default(),
+                iterm2_options: iterm2_options_from_env(is_tmux),
+                braille_options: BrailleOptions::default(),
+                ascii_options: AsciiOptions::default(),
+                color_depth: env.color_depth,
+            }),
+            Err(err) => Err(err),
         }
     }
 }
@@ -273,6 +669,56 @@ fn iterm2_from_env() -> Option<ProtocolType> {
     None
 }
 
+fn is_ssh_from_env() -> bool {
+    ["SSH_CONNECTION", "SSH_CLIENT", "SSH_TTY"]
+        .iter()
+        .any(|var| env::var(var).is_ok_and(|s| !s.is_empty()))
+}
+
+fn kitty_options_from_quirks(is_tmux: bool, kitty_direct: bool) -> KittyOptions {
+    // Classic placements need the real cursor position, which tmux does not give us.
+    let placement = if kitty_direct && !is_tmux {
+        KittyPlacement::Direct
+    } else {
+        KittyPlacement::Virtual
+    };
+    KittyOptions {
+        placement,
+        ..KittyOptions::default()
+    }
+}
+
+fn iterm2_options_from_env(is_tmux: bool) -> Iterm2Options {
+    // tmux passthrough often truncates a single large sequence.
+    Iterm2Options {
+        multipart: is_tmux,
+        ..Iterm2Options::default()
+    }
+}
+
+fn color_depth_from_env() -> ColorDepth {
+    // The de-facto standard for truecolor support.
+    if env::var("COLORTERM").is_ok_and(|colorterm| colorterm == "truecolor" || colorterm == "24bit")
+    {
+        return ColorDepth::TrueColor;
+    }
+    match env::var("TERM") {
+        Ok(term) if term.ends_with("direct") => ColorDepth::TrueColor,
+        Ok(term) if term.ends_with("256color") => ColorDepth::Indexed256,
+        Ok(term)
+            if term == "linux"
+                || term == "ansi"
+                || term.starts_with("vt")
+                || term.ends_with("16color")
+                || term.ends_with("8color") =>
+        {
+            ColorDepth::Ansi16
+        }
+        // Most other terminals do support truecolor nowadays, even if they don't say so.
+        _ => ColorDepth::TrueColor,
+    }
+}
+
 #[cfg(not(windows))]
 fn enable_raw_mode() -> Result<impl FnOnce() -> Result<()>> {
     use rustix::termios::{self, LocalModes, OptionalActions};
@@ -361,94 +807,308 @@ fn font_size_fallback() -> Option<FontSize> {
     None
 }
 
-fn query_stdio_capabilities(is_tmux: bool) -> Result<(Option<ProtocolType>, Option<FontSize>)> {
-    // Send several control sequences at once:
-    // `_Gi=...`: Kitty graphics support.
-    // `[c`: Capabilities including sixels.
-    // `[16t`: Cell-size (perhaps we should also do `[14t`).
-    // `[1337n`: iTerm2 (some terminals implement the protocol but sadly not this custom CSI)
-    // `[5n`: Device Status Report, implemented by all terminals, ensure that there is some
-    // response and we don't hang reading forever.
-    let query = Parser::query(is_tmux);
-    io::stdout().write_all(query.as_bytes())?;
-    io::stdout().flush()?;
+/// What could be found out by querying the terminal.
+struct QueryResult {
+    protocol_type: Option<ProtocolType>,
+    font_size: Option<FontSize>,
+    kitty_medium: TransmissionMedium,
+    sixel_colors: Option<u16>,
+    sixel_max_size: Option<(u16, u16)>,
+    terminal_info: TerminalInfo,
+}
 
-    let mut parser = Parser::new();
-    let mut capabilities = vec![];
-    'out: loop {
-        let mut charbuf: [u8; 50] = [0; 50];
-        let result = io::stdin().read(&mut charbuf);
-        match result {
-            Ok(read) => {
-                for ch in charbuf.iter().take(read) {
-                    let mut more_caps = parser.push(char::from(*ch));
-                    if more_caps[..] == [Capability::Status] {
-                        break 'out;
-                    } else {
-                        capabilities.append(&mut more_caps);
-                    }
-                }
+impl QueryResult {
+    fn new(capabilities: &[Capability]) -> Result<QueryResult> {
+        if capabilities.is_empty() {
+            return Err(Errors::NoCap);
+        }
+
+        let mut proto = None;
+        let mut font_size = None;
+        let mut sixel_colors = None;
+        let mut sixel_max_size = None;
+        if capabilities.contains(&Capability::Kitty) {
+            proto = Some(ProtocolType::Kitty);
+        } else if capabilities.contains(&Capability::Iterm2) {
+            proto = Some(ProtocolType::Iterm2);
+        } else if capabilities.contains(&Capability::Sixel) {
+            proto = Some(ProtocolType::Sixel);
+        }
+
+        for cap in capabilities {
+            match *cap {
+                Capability::CellSize(Some((w, h))) => font_size = Some((w, h)),
+                Capability::SixelColors(colors) => sixel_colors = Some(colors),
+                Capability::SixelGeometry(size) => sixel_max_size = Some(size),
+                _ => {}
             }
-            Err(err) => {
-                return Err(err.into());
+        }
+
+        let kitty_medium = if capabilities.contains(&Capability::KittySharedMemory) {
+            TransmissionMedium::SharedMemory
+        } else if capabilities.contains(&Capability::KittyTempFile) {
+            TransmissionMedium::TempFile
+        } else {
+            TransmissionMedium::Direct
+        };
+
+        Ok(QueryResult {
+            protocol_type: proto,
+            font_size,
+            kitty_medium,
+            sixel_colors,
+            sixel_max_size,
+            terminal_info: TerminalInfo::from_capabilities(capabilities),
+        })
+    }
+}
+
+/// Guesses from the environment of this process, which only apply if the terminal is stdio.
+struct EnvHints {
+    is_tmux: bool,
+    /// Only used if the terminal has no entry in the quirks table.
+    protocol_type: Option<ProtocolType>,
+    color_depth: ColorDepth,
+}
+
+impl EnvHints {
+    fn from_env() -> EnvHints {
+        let (is_tmux, tmux_proto) = detect_tmux_and_outer_protocol_from_env();
+        EnvHints {
+            is_tmux,
+            protocol_type: tmux_proto.or_else(iterm2_from_env),
+            color_depth: color_depth_from_env(),
+        }
+    }
+
+    /// No guesses, for a terminal that is not stdio.
+    fn none() -> EnvHints {
+        EnvHints {
+            is_tmux: false,
+            protocol_type: None,
+            color_depth: ColorDepth::default(),
+        }
+    }
+}
+
+/// A capability query that is fed with the response as it arrives, for event loops that read
+/// from the terminal by themselves.
+///
+/// # Example
+/// ```rust
+/// use ratatui_image::picker::PickerQuery;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut query = PickerQuery::new();
+/// // Write `query.query()` to the terminal, then push what it sends back.
+/// let response = b"\x1b[?64;4c\x1b[6;14;7t\x1b[0nuser input";
+/// let consumed = query.push(response);
+/// assert_eq!(consumed, Some(response.len() - "user input".len()));
+/// let picker = query.into_picker()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct PickerQuery {
+    query: String,
+    parser: Parser,
+    capabilities: Vec<Capability>,
+    complete: bool,
+}
+
+impl Default for PickerQuery {
+    fn default() -> Self {
+        PickerQuery::new()
+    }
+}
+
+impl PickerQuery {
+    pub fn new() -> PickerQuery {
+        PickerQuery::with_query(Parser::query(false))
+    }
+
+    fn with_query(query: String) -> PickerQuery {
+        PickerQuery {
+            query,
+            parser: Parser::new(),
+            capabilities: vec![],
+            complete: false,
+        }
+    }
+
+    /// The escape sequences that must be written to the terminal.
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// Feed bytes read from the terminal.
+    ///
+    /// Once the terminal has answered the whole query, returns how many of the bytes belonged to
+    /// the response. The rest is regular input.
+    pub fn push(&mut self, bytes: &[u8]) -> Option<usize> {
+        if self.complete {
+            return Some(0);
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            let mut more_caps = self.parser.push(char::from(*byte));
+            if more_caps[..] == [Capability::Status] {
+                self.complete = true;
+                return Some(i + 1);
             }
+            self.capabilities.append(&mut more_caps);
         }
+        None
     }
 
-    if capabilities.is_empty() {
-        return Err(Errors::NoCap);
+    /// Whether the terminal has answered the whole query.
+    pub fn is_complete(&self) -> bool {
+        self.complete
     }
 
-    let mut proto = None;
-    let mut font_size = None;
-    if capabilities.contains(&Capability::Kitty) {
-        proto = Some(ProtocolType::Kitty);
-    } else if capabilities.contains(&Capability::Sixel) {
-        proto = Some(ProtocolType::Sixel);
+    /// Build the picker from the response.
+    ///
+    /// This can also be called before the query is complete, for example after a timeout, to use
+    /// what arrived so far. Without any response, this falls back to [ProtocolType::Halfblocks].
+    pub fn into_picker(self) -> Result<Picker> {
+        Picker::from_query_result(QueryResult::new(&self.capabilities), EnvHints::none())
     }
+}
 
-    for cap in capabilities {
-        if let Capability::CellSize(Some((w, h))) = cap {
-            font_size = Some((w, h));
+/// Read from the terminal until it has answered the whole query, closed, or the deadline has
+/// passed. Returns the bytes that were read after the response.
+fn read_response(
+    reader: &mut impl Read,
+    query: &mut PickerQuery,
+    deadline: Option<Instant>,
+) -> Result<Vec<u8>> {
+    let mut charbuf: [u8; 50] = [0; 50];
+    loop {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+        let read = match reader.read(&mut charbuf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(consumed) = query.push(&charbuf[..read]) {
+            return Ok(charbuf[consumed..read].to_vec());
         }
     }
-    // In case some terminal didn't support the cell-size query.
-    font_size = font_size.or_else(font_size_fallback);
+    Ok(vec![])
+}
+
+/// Stdin that only waits for input until the deadline, so that a terminal that never answers
+/// doesn't block the query forever.
+struct StdinUntil(Instant);
+
+#[cfg(not(windows))]
+impl Read for StdinUntil {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use rustix::event::{poll, PollFd, PollFlags};
 
-    Ok((proto, font_size))
+        let stdin = io::stdin();
+        let timeout = self.0.saturating_duration_since(Instant::now());
+        let mut fds = [PollFd::new(&stdin, PollFlags::IN)];
+        if poll(&mut fds, timeout.as_millis().min(i32::MAX as u128) as i32)? == 0 {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        // Read the fd directly, the buffer of `Stdin` would keep the input after the response.
+        Ok(rustix::io::read(&stdin, buf)?)
+    }
 }
 
-fn query_with_timeout(
-    is_tmux: bool,
-    timeout: Duration,
-) -> Result<(Option<ProtocolType>, Option<FontSize>)> {
-    use std::{sync::mpsc, thread};
-    let (tx, rx) = mpsc::channel();
-
-    thread::spawn(move || {
-        let _ = tx.send(
-            enable_raw_mode()
-                .map_err(Errors::into)
-                .and_then(|disable_raw_mode| {
-                    let result = query_stdio_capabilities(is_tmux);
-                    // Always try to return to raw_mode.
-                    disable_raw_mode()?;
-                    result
-                }),
-        );
-    });
+#[cfg(windows)]
+impl Read for StdinUntil {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use std::os::windows::io::AsRawHandle;
+        use windows::Win32::{
+            Foundation::{HANDLE, WAIT_OBJECT_0},
+            System::Threading::WaitForSingleObject,
+        };
 
-    match rx.recv_timeout(timeout) {
-        Ok(result) => Ok(result?),
-        Err(_recvtimeout) => Err(Errors::NoStdinResponse),
+        let stdin = io::stdin();
+        let timeout = self.0.saturating_duration_since(Instant::now());
+        let timeout = timeout.as_millis().min(u32::MAX as u128) as u32;
+        if unsafe { WaitForSingleObject(HANDLE(stdin.as_raw_handle()), timeout) } != WAIT_OBJECT_0 {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        stdin.lock().read(buf)
     }
 }
 
+fn query_stdio_capabilities(is_tmux: bool, deadline: Instant) -> Result<(QueryResult, Vec<u8>)> {
+    // Send several control sequences at once:
+    // `_Gi=...`: Kitty graphics support.
+    // `[c`: Capabilities including sixels.
+    // `[?1;1;0S`, `[?2;1;0S`: Number of sixel color registers, and maximum sixel geometry.
+    // `[>0q`, `[>c`: Terminal name and version (XTVERSION), and terminal type (DA2).
+    // `[16t`: Cell-size (perhaps we should also do `[14t`).
+    // `]10;?`, `]11;?`: Default foreground and background colors.
+    // `[1337n`, `]1337;ReportCellSize`: iTerm2 (some terminals implement the protocol but sadly
+    // not these custom sequences).
+    // `[5n`: Device Status Report, implemented by all terminals, ensure that there is some
+    // response and we don't hang reading forever.
+    // Before that, Kitty transmission media that only work locally: `t=t` and `t=s`. Over ssh the
+    // terminal can't read our files, and inside tmux we don't know where the terminal runs.
+    let probes = if is_tmux || is_ssh_from_env() {
+        (None, None)
+    } else {
+        (
+            MediumFile::probe(TransmissionMedium::TempFile),
+            MediumFile::probe(TransmissionMedium::SharedMemory),
+        )
+    };
+    let mut query = Parser::query_kitty_media(
+        probes.0.as_ref().map(MediumFile::path),
+        probes.1.as_ref().map(MediumFile::path),
+    );
+    query.push_str(&Parser::query(is_tmux));
+    let mut query = PickerQuery::with_query(query);
+    io::stdout().write_all(query.query().as_bytes())?;
+    io::stdout().flush()?;
+
+    let input = read_response(&mut StdinUntil(deadline), &mut query, Some(deadline))?;
+    if !query.is_complete() {
+        return Err(Errors::NoStdinResponse);
+    }
+    let mut result = QueryResult::new(&query.capabilities)?;
+    // In case some terminal didn't support the cell-size query.
+    result.font_size = result.font_size.or_else(font_size_fallback);
+    Ok((result, input))
+}
+
+fn query_with_timeout(is_tmux: bool, timeout: Duration) -> Result<(QueryResult, Vec<u8>)> {
+    let disable_raw_mode = enable_raw_mode()?;
+    let result = query_stdio_capabilities(is_tmux, Instant::now() + timeout);
+    // Always try to return to raw_mode.
+    disable_raw_mode()?;
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use std::assert_eq;
 
-    use crate::picker::{Picker, ProtocolType};
+    use std::{
+        io::{self, Cursor, ErrorKind, Read},
+        time::Duration,
+    };
+
+    use image::Rgba;
+
+    use crate::{
+        picker::{
+            cap_parser::Capability, terminal::Terminal, EnvHints, Picker, PickerQuery,
+            ProtocolType, QueryResult,
+        },
+        protocol::{kitty::KittyPlacement, sixel::SixelOptions},
+    };
 
     #[test]
     fn test_cycle_protocol() {
@@ -460,9 +1120,111 @@ mod tests {
         proto = proto.next();
         assert_eq!(proto, ProtocolType::Iterm2);
         proto = proto.next();
+        assert_eq!(proto, ProtocolType::Quadrants);
+        proto = proto.next();
+        assert_eq!(proto, ProtocolType::Sextants);
+        proto = proto.next();
+        assert_eq!(proto, ProtocolType::Octants);
+        proto = proto.next();
+        assert_eq!(proto, ProtocolType::Braille);
+        proto = proto.next();
+        assert_eq!(proto, ProtocolType::Ascii);
+        proto = proto.next();
         assert_eq!(proto, ProtocolType::Halfblocks);
     }
 
+    #[test]
+    fn test_capped_sixel_options() {
+        let mut picker = Picker::from_fontsize((10, 20));
+        picker.sixel_colors = Some(16);
+        picker.sixel_max_size = Some((1000, 800));
+        let options = picker.capped_sixel_options();
+        assert_eq!(options.colors, 16);
+        assert_eq!(options.max_size, Some((1000, 800)));
+
+        picker.set_sixel_options(SixelOptions {
+            max_size: Some((2000, 500)),
+            ..SixelOptions::default()
+        });
+        assert_eq!(picker.capped_sixel_options().max_size, Some((1000, 500)));
+    }
+
+    #[test]
+    fn test_from_query() {
+        let response = "\x1bP>|foot(1.16.2)\x1b\\\x1b[?62;4c\x1b[6;20;10t\x1b[0nuser input";
+        let mut writer = vec![];
+        let (picker, input) =
+            Picker::from_query(Cursor::new(response), &mut writer, Duration::from_secs(1)).unwrap();
+        assert_eq!(input, b"user input");
+        assert_eq!(
+            String::from_utf8(writer).unwrap(),
+            PickerQuery::new().query()
+        );
+        assert_eq!(picker.protocol_type(), ProtocolType::Sixel);
+        assert_eq!(picker.font_size(), (10, 20));
+        assert_eq!(picker.terminal_info().terminal, Terminal::Foot);
+
+        // Konsole implements the graphics protocol, but not unicode placeholders.
+        let response =
+            "\x1b_Gi=31;OK\x1b\\\x1bP>|Konsole 23.08.1\x1b\\\x1b[?62;4c\x1b[6;20;10t\x1b[0n";
+        let (picker, _) =
+            Picker::from_query(Cursor::new(response), &mut vec![], Duration::from_secs(1)).unwrap();
+        assert_eq!(picker.protocol_type(), ProtocolType::Kitty);
+        assert_eq!(picker.kitty_options().placement, KittyPlacement::Direct);
+
+        // XTerm has no quirks, so a hint from the environment still applies.
+        let capabilities = [
+            Capability::TerminalVersion("XTerm(388)".to_string()),
+            Capability::Sixel,
+            Capability::CellSize(Some((10, 20))),
+        ];
+        let env = EnvHints {
+            protocol_type: Some(ProtocolType::Iterm2),
+            ..EnvHints::none()
+        };
+        let picker = Picker::from_query_result(QueryResult::new(&capabilities), env).unwrap();
+        assert_eq!(picker.protocol_type(), ProtocolType::Iterm2);
+
+        // A closed terminal that never answered.
+        let (picker, _) =
+            Picker::from_query(Cursor::new(""), &mut vec![], Duration::from_secs(1)).unwrap();
+        assert_eq!(picker.protocol_type(), ProtocolType::Halfblocks);
+
+        // A terminal that stopped answering before the status report.
+        struct Pending;
+        impl Read for Pending {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(ErrorKind::WouldBlock.into())
+            }
+        }
+        let response = "\x1b[?62;4c\x1b[6;20;10t";
+        let reader = Cursor::new(response).chain(Pending);
+        let (picker, _) =
+            Picker::from_query(reader, &mut vec![], Duration::from_millis(50)).unwrap();
+        assert_eq!(picker.protocol_type(), ProtocolType::Sixel);
+        assert_eq!(picker.font_size(), (10, 20));
+    }
+
+    #[test]
+    fn test_background_color() {
+        let capabilities = [
+            Capability::Sixel,
+            Capability::CellSize(Some((10, 20))),
+            Capability::BackgroundColor([40, 44, 52]),
+        ];
+        let mut picker =
+            Picker::from_query_result(QueryResult::new(&capabilities), EnvHints::none()).unwrap();
+        assert_eq!(picker.protocol_type(), ProtocolType::Sixel);
+        assert_eq!(picker.background_color(), Rgba([40, 44, 52, 255]));
+
+        // Kitty can draw transparent padding.
+        picker.set_protocol_type(ProtocolType::Kitty);
+        assert_eq!(picker.background_color(), Rgba([0, 0, 0, 0]));
+
+        picker.set_background_color([255, 255, 255, 255]);
+        assert_eq!(picker.background_color(), Rgba([255, 255, 255, 255]));
+    }
+
     #[test]
     fn test_from_query_stdio_no_hang() {
         let _ = Picker::from_query_stdio();
@@ -1,5 +1,7 @@
 use std::fmt::Write;
 
+use base64::{engine::general_purpose, Engine};
+
 pub struct Parser {
     data: String,
     sequence: Response,
@@ -10,6 +12,11 @@ pub enum Response {
     Unknown,
     Kitty,
     DeviceAttributes,
+    SecondaryDeviceAttributes,
+    TerminalVersion,
+    Iterm2Version,
+    Iterm2CellSize,
+    DefaultColor,
     CellSize,
     Status,
 }
@@ -17,9 +24,28 @@ pub enum Response {
 #[derive(Debug, PartialEq)]
 pub enum Capability {
     Kitty,
+    /// The terminal could read a temporary file, see [Parser::query_kitty_media].
+    KittyTempFile,
+    /// The terminal could read a shared memory object, see [Parser::query_kitty_media].
+    KittySharedMemory,
     Sixel,
+    /// The terminal answered an iTerm2 proprietary query, so it implements the inline images
+    /// protocol.
+    Iterm2,
+    /// The number of sixel color registers, from XTSMGRAPHICS.
+    SixelColors(u16),
+    /// The maximum sixel image size in pixels, from XTSMGRAPHICS.
+    SixelGeometry((u16, u16)),
     RectangularOps,
+    /// The terminal name and version from XTVERSION, e.g. `XTerm(388)`.
+    TerminalVersion(String),
+    /// The terminal type and firmware version from DA2.
+    SecondaryDeviceAttributes(u16, u32),
     CellSize(Option<(u16, u16)>),
+    /// The default foreground color, from OSC 10.
+    ForegroundColor([u8; 3]),
+    /// The default background color, from OSC 11.
+    BackgroundColor([u8; 3]),
     Status, // Might as well call this "End" internally.
 }
 
@@ -64,11 +90,21 @@ impl Parser {
         // Device Attributes Report 1 (sixel support)
         write!(buf, "{escape}[c").unwrap();
 
+        // XTSMGRAPHICS: number of sixel color registers, and maximum sixel geometry.
+        write!(buf, "{escape}[?1;1;0S{escape}[?2;1;0S").unwrap();
+
+        // XTVERSION (terminal name and version) and Device Attributes Report 2 (terminal type).
+        write!(buf, "{escape}[>0q{escape}[>c").unwrap();
+
+        // iTerm2 proprietary: terminal name and version, and cell size in points. Sent before the
+        // font size in pixels, which is more accurate if both are answered.
+        write!(buf, "{escape}[1337n{escape}]1337;ReportCellSize\x07").unwrap();
+
         // Font size in pixels
         write!(buf, "{escape}[16t").unwrap();
 
-        // iTerm2 proprietary, unknown response, untested so far.
-        //write!(buf, "{escape}[1337n").unwrap();
+        // Default foreground and background colors.
+        write!(buf, "{escape}]10;?\x07{escape}]11;?\x07").unwrap();
 
         // End with Device Status Report, implemented by all terminals, ensure that there is some
         // response and we don't hang reading forever.
@@ -77,6 +113,21 @@ impl Parser {
         write!(buf, "{end}").unwrap();
         buf
     }
+    /// Query the terminal for reading image data from a temporary file and/or from shared
+    /// memory, which is only possible if it runs on the same machine.
+    ///
+    /// The paths must contain a single RGB pixel. This should be sent before [Parser::query], so
+    /// that the responses arrive before the final status report.
+    pub fn query_kitty_media(temp_file: Option<&str>, shared_memory: Option<&str>) -> String {
+        let mut buf = String::new();
+        for (id, t, path) in [(32, 't', temp_file), (33, 's', shared_memory)] {
+            if let Some(path) = path {
+                let payload = general_purpose::STANDARD.encode(path);
+                write!(buf, "\x1b_Gi={id},s=1,v=1,a=q,t={t},f=24;{payload}\x1b\\").unwrap();
+            }
+        }
+        buf
+    }
     pub fn push(&mut self, next: char) -> Vec<Capability> {
         match self.sequence {
             Response::Unknown => {
@@ -88,7 +139,22 @@ impl Parser {
                     ("[", '?') => {
                         self.sequence = Response::DeviceAttributes;
                     }
-                    ("_Gi=31", ';') => {
+                    ("[", '>') => {
+                        self.sequence = Response::SecondaryDeviceAttributes;
+                    }
+                    ("P", '>') => {
+                        self.sequence = Response::TerminalVersion;
+                    }
+                    ("[", 'I') => {
+                        self.sequence = Response::Iterm2Version;
+                    }
+                    ("]1337", ';') => {
+                        self.sequence = Response::Iterm2CellSize;
+                    }
+                    ("]10", ';') | ("]11", ';') => {
+                        self.sequence = Response::DefaultColor;
+                    }
+                    (data, ';') if data.starts_with("_Gi=") => {
                         self.sequence = Response::Kitty;
                     }
                     ("[6", ';') => {
@@ -115,6 +181,93 @@ impl Parser {
                     self.restart();
                     return caps;
                 }
+                // XTSMGRAPHICS also starts with `[?`: item, status (0 is success), values.
+                'S' => {
+                    let inner: Vec<&str> = (self.data[2..]).split(';').collect();
+                    let caps = match inner[..] {
+                        ["1", "0", colors] => colors
+                            .parse::<u16>()
+                            .ok()
+                            .filter(|colors| *colors > 0)
+                            .map(Capability::SixelColors),
+                        ["2", "0", w, h] => match (w.parse::<u16>(), h.parse::<u16>()) {
+                            (Ok(w), Ok(h)) if w > 0 && h > 0 => {
+                                Some(Capability::SixelGeometry((w, h)))
+                            }
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    self.restart();
+                    return caps.into_iter().collect();
+                }
+                '\x1b' => {
+                    return self.restart();
+                }
+                _ => {
+                    self.data.push(next);
+                }
+            },
+
+            Response::SecondaryDeviceAttributes => match next {
+                'c' => {
+                    let inner: Vec<&str> = (self.data[2..]).split(';').collect();
+                    let caps = match inner[..] {
+                        [terminal_type, firmware, ..] => {
+                            match (terminal_type.parse::<u16>(), firmware.parse::<u32>()) {
+                                (Ok(terminal_type), Ok(firmware)) => {
+                                    vec![Capability::SecondaryDeviceAttributes(
+                                        terminal_type,
+                                        firmware,
+                                    )]
+                                }
+                                _ => vec![],
+                            }
+                        }
+                        _ => vec![],
+                    };
+                    self.restart();
+                    return caps;
+                }
+                '\x1b' => {
+                    return self.restart();
+                }
+                _ => {
+                    self.data.push(next);
+                }
+            },
+
+            Response::TerminalVersion => match next {
+                '\\' if self.data.ends_with('\x1b') => {
+                    let caps = match self.data.strip_prefix("P>|") {
+                        Some(version) => vec![Capability::TerminalVersion(
+                            version.trim_end_matches('\x1b').to_string(),
+                        )],
+                        None => vec![],
+                    };
+                    self.restart();
+                    return caps;
+                }
+                // Esc without `\` starts another sequence, e.g. if the version was cut off.
+                _ if self.data.ends_with('\x1b') => {
+                    self.restart();
+                    return self.push(next);
+                }
+                _ => {
+                    self.data.push(next);
+                }
+            },
+
+            Response::Iterm2Version => match next {
+                'n' => {
+                    let caps = if self.data.starts_with("[ITERM2") {
+                        vec![Capability::Iterm2]
+                    } else {
+                        vec![]
+                    };
+                    self.restart();
+                    return caps;
+                }
                 '\x1b' => {
                     return self.restart();
                 }
@@ -123,10 +276,49 @@ impl Parser {
                 }
             },
 
+            Response::Iterm2CellSize => match next {
+                // Terminated by either BEL or ST.
+                '\x07' | '\\' => {
+                    let caps = match self.data.strip_prefix("]1337;ReportCellSize=") {
+                        Some(size) => vec![
+                            Capability::Iterm2,
+                            Capability::CellSize(parse_iterm2_cell_size(
+                                size.trim_end_matches('\x1b'),
+                            )),
+                        ],
+                        None => vec![],
+                    };
+                    self.restart();
+                    return caps;
+                }
+                _ => {
+                    self.data.push(next);
+                }
+            },
+
+            Response::DefaultColor => match next {
+                // Terminated by either BEL or ST.
+                '\x07' | '\\' => {
+                    let data = self.data.trim_end_matches('\x1b');
+                    let caps = match (data.get(..4), data.get(4..).and_then(parse_rgb)) {
+                        (Some("]10;"), Some(rgb)) => vec![Capability::ForegroundColor(rgb)],
+                        (Some("]11;"), Some(rgb)) => vec![Capability::BackgroundColor(rgb)],
+                        _ => vec![],
+                    };
+                    self.restart();
+                    return caps;
+                }
+                _ => {
+                    self.data.push(next);
+                }
+            },
+
             Response::Kitty => match next {
                 '\\' => {
                     let caps = match &self.data[..] {
                         "_Gi=31;OK\x1b" => vec![Capability::Kitty],
+                        "_Gi=32;OK\x1b" => vec![Capability::KittyTempFile],
+                        "_Gi=33;OK\x1b" => vec![Capability::KittySharedMemory],
                         _ => vec![],
                     };
                     self.restart();
@@ -177,6 +369,43 @@ impl Parser {
     }
 }
 
+/// Parse an X11 color specification `rgb:R/G/B`, with 1 to 4 hex digits per component.
+fn parse_rgb(spec: &str) -> Option<[u8; 3]> {
+    let components: Vec<&str> = spec.strip_prefix("rgb:")?.split('/').collect();
+    let [r, g, b] = components[..] else {
+        return None;
+    };
+    let scale = |component: &str| {
+        if component.is_empty() || component.len() > 4 {
+            return None;
+        }
+        let value = u32::from_str_radix(component, 16).ok()?;
+        let max = (1 << (4 * component.len())) - 1;
+        Some(((value * 255 + max / 2) / max) as u8)
+    };
+    Some([scale(r)?, scale(g)?, scale(b)?])
+}
+
+/// Parse `height;width[;scale]` in points into the cell size in pixels.
+fn parse_iterm2_cell_size(size: &str) -> Option<(u16, u16)> {
+    let values: Vec<f32> = size
+        .split(';')
+        .map(str::parse::<f32>)
+        .collect::<std::result::Result<_, _>>()
+        .ok()?;
+    let (h, w, scale) = match values[..] {
+        [h, w] => (h, w, 1.0),
+        [h, w, scale] => (h, w, scale),
+        _ => return None,
+    };
+    let (w, h) = ((w * scale).round(), (h * scale).round());
+    if w >= 1.0 && h >= 1.0 && w <= u16::MAX as f32 && h <= u16::MAX as f32 {
+        Some((w as u16, h as u16))
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::assert_eq;
@@ -185,7 +414,7 @@ mod tests {
 
     #[test]
     fn test_parse_all() {
-        for (name, str, expected) in vec![
+        for (name, str, expected) in [
             (
                 "all",
                 "\x1b_Gi=31;OK\x1b\\\x1b[?64;4c\x1b[6;7;14t\x1b[0n",
@@ -196,6 +425,61 @@ mod tests {
                     Capability::Status,
                 ],
             ),
+            (
+                "kitty media",
+                "\x1b_Gi=32;OK\x1b\\\x1b_Gi=33;ENOENT:No such file\x1b\\\x1b_Gi=31;OK\x1b\\\x1b[0n",
+                vec![
+                    Capability::KittyTempFile,
+                    Capability::Kitty,
+                    Capability::Status,
+                ],
+            ),
+            (
+                "sixel graphics",
+                "\x1b[?64;4c\x1b[?1;0;1024S\x1b[?2;0;1000;800S\x1b[?2;3;0S\x1b[0n",
+                vec![
+                    Capability::Sixel,
+                    Capability::SixelColors(1024),
+                    Capability::SixelGeometry((1000, 800)),
+                    Capability::Status,
+                ],
+            ),
+            (
+                "terminal version",
+                "\x1bP>|XTerm(388)\x1b\\\x1b[>41;388;0c\x1b[0n",
+                vec![
+                    Capability::TerminalVersion("XTerm(388)".to_string()),
+                    Capability::SecondaryDeviceAttributes(41, 388),
+                    Capability::Status,
+                ],
+            ),
+            (
+                "cut off terminal version",
+                "\x1bP>|XTerm(388\x1b[>41;388;0c\x1b[0n",
+                vec![
+                    Capability::SecondaryDeviceAttributes(41, 388),
+                    Capability::Status,
+                ],
+            ),
+            (
+                "iterm2",
+                "\x1b[ITERM2 3.5.0n\x1b]1337;ReportCellSize=17.00;8.00;2.00\x1b\\\x1b[0n",
+                vec![
+                    Capability::Iterm2,
+                    Capability::Iterm2,
+                    Capability::CellSize(Some((16, 34))),
+                    Capability::Status,
+                ],
+            ),
+            (
+                "default colors",
+                "\x1b]10;rgb:ffff/ffff/ffff\x1b\\\x1b]11;rgb:28/2c/34\x07\x1b[0n",
+                vec![
+                    Capability::ForegroundColor([255, 255, 255]),
+                    Capability::BackgroundColor([40, 44, 52]),
+                    Capability::Status,
+                ],
+            ),
             ("only garbage", "\x1bhonkey\x1btonkey\x1b[42\x1b\\", vec![]),
             (
                 "preceding garbage",
@@ -0,0 +1,247 @@
+//! Terminal identification from the XTVERSION and DA2 responses, and known quirks. Also the
+//! default colors from OSC 10 and 11.
+use super::{cap_parser::Capability, ProtocolType};
+
+/// Terminals that are known to need or prefer something.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Terminal {
+    /// The terminal did not identify itself, or is not in the list.
+    #[default]
+    Unknown,
+    XTerm,
+    Kitty,
+    Ghostty,
+    WezTerm,
+    Foot,
+    Konsole,
+    Mlterm,
+    Contour,
+    ITerm2,
+    Mintty,
+}
+
+/// What the terminal told about itself.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TerminalInfo {
+    pub terminal: Terminal,
+    /// The leading numeric components of the XTVERSION version, e.g. `(0, 35, 2)` for
+    /// `kitty(0.35.2)` or `(388, 0, 0)` for `XTerm(388)`.
+    pub version: Option<(u32, u32, u32)>,
+    /// The terminal type and firmware version of the DA2 response.
+    pub device_attributes: Option<(u16, u32)>,
+    /// The default foreground color.
+    pub foreground: Option<[u8; 3]>,
+    /// The default background color.
+    pub background: Option<[u8; 3]>,
+}
+
+/// Protocols and workarounds for a [Terminal].
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Quirks {
+    /// Use this protocol, regardless of the detected capabilities.
+    pub protocol: Option<ProtocolType>,
+    /// The Kitty graphics protocol is implemented without unicode placeholders.
+    pub kitty_direct: bool,
+}
+
+const QUIRKS: [(Terminal, Quirks); 8] = [
+    // Also implements Sixel and Kitty, but only iTerm2 works bug-free (see the compatibility
+    // matrix in the README). Its Kitty implementation has no unicode placeholders.
+    (
+        Terminal::WezTerm,
+        Quirks {
+            protocol: Some(ProtocolType::Iterm2),
+            kitty_direct: true,
+        },
+    ),
+    // Implements Kitty with unicode placeholders, which should win over any env var hint.
+    (
+        Terminal::Ghostty,
+        Quirks {
+            protocol: Some(ProtocolType::Kitty),
+            kitty_direct: false,
+        },
+    ),
+    // Implements Kitty without unicode placeholders, the protocol is left to the detection.
+    (
+        Terminal::Konsole,
+        Quirks {
+            protocol: None,
+            kitty_direct: true,
+        },
+    ),
+    // Sixel is the only protocol these implement, so env var hints (e.g. leaked over ssh) must
+    // not select iTerm2.
+    (
+        Terminal::Foot,
+        Quirks {
+            protocol: Some(ProtocolType::Sixel),
+            kitty_direct: false,
+        },
+    ),
+    (
+        Terminal::Mlterm,
+        Quirks {
+            protocol: Some(ProtocolType::Sixel),
+            kitty_direct: false,
+        },
+    ),
+    (
+        Terminal::Contour,
+        Quirks {
+            protocol: Some(ProtocolType::Sixel),
+            kitty_direct: false,
+        },
+    ),
+    // Also implement Sixel, but iTerm2 is their native protocol, and what the env var detection
+    // used to pick for them.
+    (
+        Terminal::ITerm2,
+        Quirks {
+            protocol: Some(ProtocolType::Iterm2),
+            kitty_direct: false,
+        },
+    ),
+    (
+        Terminal::Mintty,
+        Quirks {
+            protocol: Some(ProtocolType::Iterm2),
+            kitty_direct: false,
+        },
+    ),
+];
+
+/// XTVERSION names, compared case-insensitively.
+const NAMES: [(&str, Terminal); 10] = [
+    ("xterm", Terminal::XTerm),
+    ("kitty", Terminal::Kitty),
+    ("ghostty", Terminal::Ghostty),
+    ("wezterm", Terminal::WezTerm),
+    ("foot", Terminal::Foot),
+    ("konsole", Terminal::Konsole),
+    ("mlterm", Terminal::Mlterm),
+    ("contour", Terminal::Contour),
+    ("iterm2", Terminal::ITerm2),
+    ("mintty", Terminal::Mintty),
+];
+
+impl TerminalInfo {
+    /// Collect the XTVERSION, DA2 and default color responses. XTVERSION is preferred, DA2 only
+    /// identifies terminals with a distinctive terminal type.
+    pub fn from_capabilities(capabilities: &[Capability]) -> TerminalInfo {
+        let mut info = TerminalInfo::default();
+        for cap in capabilities {
+            match cap {
+                Capability::TerminalVersion(version) => {
+                    let (terminal, version) = parse_xtversion(version);
+                    info.terminal = terminal;
+                    info.version = version;
+                }
+                Capability::SecondaryDeviceAttributes(terminal_type, firmware) => {
+                    info.device_attributes = Some((*terminal_type, *firmware));
+                }
+                Capability::ForegroundColor(rgb) => info.foreground = Some(*rgb),
+                Capability::BackgroundColor(rgb) => info.background = Some(*rgb),
+                _ => {}
+            }
+        }
+        if info.terminal == Terminal::Unknown {
+            info.terminal = match info.device_attributes {
+                Some((24, _)) => Terminal::Mlterm,
+                Some((77, _)) => Terminal::Mintty,
+                _ => Terminal::Unknown,
+            };
+        }
+        info
+    }
+
+    /// Whether the terminal has a dark background, or light text if only the foreground color is
+    /// known. `None` if the terminal did not report its colors.
+    pub fn is_dark(&self) -> Option<bool> {
+        match (self.background, self.foreground) {
+            (Some(background), _) => Some(luma(background) < 128.0),
+            (None, Some(foreground)) => Some(luma(foreground) >= 128.0),
+            (None, None) => None,
+        }
+    }
+
+    /// The entry of the quirks table, if the terminal has one.
+    pub(crate) fn quirks(&self) -> Option<Quirks> {
+        QUIRKS
+            .iter()
+            .find(|(terminal, _)| *terminal == self.terminal)
+            .map(|(_, quirks)| *quirks)
+    }
+}
+
+fn luma([r, g, b]: [u8; 3]) -> f32 {
+    0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32
+}
+
+/// Split `name(version)` or `name version` into the known terminal and the numeric version.
+fn parse_xtversion(response: &str) -> (Terminal, Option<(u32, u32, u32)>) {
+    let (name, version) = response.split_once(['(', ' ']).unwrap_or((response, ""));
+    let terminal = NAMES
+        .iter()
+        .find(|(known, _)| name.eq_ignore_ascii_case(known))
+        .map(|(_, terminal)| *terminal)
+        .unwrap_or_default();
+
+    let mut numbers = version
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<u32>().ok());
+    let version = numbers.next().flatten().map(|major| {
+        let mut next = || numbers.next().flatten().unwrap_or(0);
+        (major, next(), next())
+    });
+    (terminal, version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Terminal, TerminalInfo};
+    use crate::picker::{cap_parser::Capability, ProtocolType};
+
+    #[test]
+    fn test_from_capabilities() {
+        for (response, terminal, version) in [
+            ("XTerm(388)", Terminal::XTerm, Some((388, 0, 0))),
+            ("kitty(0.35.2)", Terminal::Kitty, Some((0, 35, 2))),
+            (
+                "WezTerm 20240203-110809-5046fc22",
+                Terminal::WezTerm,
+                Some((20240203, 110809, 5046)),
+            ),
+            ("foot(1.16.2)", Terminal::Foot, Some((1, 16, 2))),
+            ("ghostty 1.1.3", Terminal::Ghostty, Some((1, 1, 3))),
+            ("some-terminal", Terminal::Unknown, None),
+        ] {
+            let info =
+                TerminalInfo::from_capabilities(&[Capability::TerminalVersion(response.into())]);
+            assert_eq!(info.terminal, terminal, "{response}");
+            assert_eq!(info.version, version, "{response}");
+        }
+
+        let info =
+            TerminalInfo::from_capabilities(&[Capability::SecondaryDeviceAttributes(77, 30601)]);
+        assert_eq!(info.terminal, Terminal::Mintty);
+        assert_eq!(
+            info.quirks().and_then(|quirks| quirks.protocol),
+            Some(ProtocolType::Iterm2)
+        );
+        assert_eq!(info.is_dark(), None);
+    }
+
+    #[test]
+    fn test_is_dark() {
+        let info = TerminalInfo::from_capabilities(&[
+            Capability::ForegroundColor([255, 255, 255]),
+            Capability::BackgroundColor([40, 44, 52]),
+        ]);
+        assert_eq!(info.is_dark(), Some(true));
+
+        let info = TerminalInfo::from_capabilities(&[Capability::ForegroundColor([0, 0, 0])]);
+        assert_eq!(info.is_dark(), Some(false));
+    }
+}
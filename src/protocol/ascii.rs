@@ -0,0 +1,280 @@
+//! ASCII-art protocol implementations.
+//! Maps the luminance of each cell to a character of a ramp, and optionally draws edges with
+//! `|/-\`. The output is plain text that can be copied, logged, or shown on dumb terminals.
+//!
+//! The foreground color can optionally be set to the color of the cell, in any [ColorDepth].
+use image::{imageops::FilterType, DynamicImage, Rgba};
+use ratatui::{buffer::Buffer, layout::Rect, style::Color};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::{color::ColorDepth, EncodeFailure, ProtocolTrait, StatefulProtocolTrait};
+use crate::{FontSize, ImageSource, Resize, Result};
+
+/// Characters from dark to light, for light text on a dark background.
+pub const DEFAULT_RAMP: &str = " .:-=+*#%@";
+
+/// Sobel gradient magnitude above which a cell is drawn as an edge.
+const EDGE_THRESHOLD: f32 = 255.0;
+
+/// Options for the [Ascii] protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct AsciiOptions {
+    /// Characters from dark to light. Reverse it for dark text on a light background.
+    ///
+    /// Deserializing leaks the string, which is fine for the few ramps of an application.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "deserialize_ramp"))]
+    pub ramp: &'static str,
+    /// Draw strong edges with `|/-\` along their direction, instead of the ramp.
+    pub edges: bool,
+    /// The depth of the foreground colors, or `None` for no colors at all.
+    pub color: Option<ColorDepth>,
+}
+
+impl Default for AsciiOptions {
+    fn default() -> Self {
+        AsciiOptions {
+            ramp: DEFAULT_RAMP,
+            edges: false,
+            color: None,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_ramp<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<&'static str, D::Error> {
+    let ramp = String::deserialize(deserializer)?;
+    Ok(Box::leak(ramp.into_boxed_str()))
+}
+
+// Fixed Ascii protocol
+#[derive(Clone, Default)]
+pub struct Ascii {
+    data: Vec<AsciiCell>,
+    area: Rect,
+    options: AsciiOptions,
+}
+
+#[derive(Clone, Debug)]
+struct AsciiCell {
+    symbol: char,
+    fg: Option<Color>,
+}
+
+impl Ascii {
+    /// Create an Ascii from an image.
+    ///
+    /// The "resolution" is determined by the font size of the terminal, see
+    /// [super::halfblocks::Halfblocks::new].
+    pub fn new(image: DynamicImage, area: Rect, options: AsciiOptions) -> Result<Self> {
+        let data = encode(&image, area, &options);
+        Ok(Self {
+            data,
+            area,
+            options,
+        })
+    }
+}
+
+fn encode(img: &DynamicImage, rect: Rect, options: &AsciiOptions) -> Vec<AsciiCell> {
+    let (width, height) = (rect.width as usize, rect.height as usize);
+    let img = img
+        .resize_exact(width as u32, height as u32, FilterType::Triangle)
+        .to_rgba8();
+
+    // Luminance, premultiplied by alpha so that transparent pixels are dark.
+    let luma: Vec<f32> = img
+        .pixels()
+        .map(|p| {
+            (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32) * p[3] as f32 / 255.0
+        })
+        .collect();
+    let ramp: Vec<char> = options.ramp.chars().collect();
+
+    let mut data = Vec::with_capacity(width * height);
+    for (i, pixel) in img.pixels().enumerate() {
+        let (x, y) = (i % width, i / width);
+        let edge = if options.edges {
+            edge_symbol(&luma, width, height, x, y)
+        } else {
+            None
+        };
+        let symbol = edge.unwrap_or_else(|| {
+            let index = (luma[i] / 256.0 * ramp.len() as f32) as usize;
+            ramp.get(index.min(ramp.len().saturating_sub(1)))
+                .copied()
+                .unwrap_or(' ')
+        });
+        let fg = options
+            .color
+            .map(|depth| depth.quantize([pixel[0], pixel[1], pixel[2]]).0);
+        data.push(AsciiCell { symbol, fg });
+    }
+    data
+}
+
+/// The character along the edge at (x, y), if the Sobel gradient is strong enough.
+fn edge_symbol(luma: &[f32], width: usize, height: usize, x: usize, y: usize) -> Option<char> {
+    let at = |dx: isize, dy: isize| {
+        let x = (x as isize + dx).clamp(0, width as isize - 1) as usize;
+        let y = (y as isize + dy).clamp(0, height as isize - 1) as usize;
+        luma[y * width + x]
+    };
+    let gx = at(1, -1) + 2.0 * at(1, 0) + at(1, 1) - at(-1, -1) - 2.0 * at(-1, 0) - at(-1, 1);
+    let gy = at(-1, 1) + 2.0 * at(0, 1) + at(1, 1) - at(-1, -1) - 2.0 * at(0, -1) - at(1, -1);
+    if gx.hypot(gy) < EDGE_THRESHOLD {
+        return None;
+    }
+    // The edge runs perpendicular to the gradient, and y points down.
+    let angle = gy.atan2(gx).to_degrees().rem_euclid(180.0);
+    Some(match angle {
+        a if a < 22.5 => '|',
+        a if a < 67.5 => '/',
+        a if a < 112.5 => '-',
+        a if a < 157.5 => '\\',
+        _ => '|',
+    })
+}
+
+impl ProtocolTrait for Ascii {
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        for (i, ascii) in self.data.iter().enumerate() {
+            let x = i as u16 % self.area.width;
+            let y = i as u16 / self.area.width;
+            if x >= area.width || y >= area.height {
+                continue;
+            }
+
+            if let Some(cell) = buf.cell_mut((area.x + x, area.y + y)) {
+                cell.set_char(ascii.symbol);
+                if let Some(fg) = ascii.fg {
+                    cell.set_fg(fg);
+                }
+            }
+        }
+    }
+    fn area(&self) -> Rect {
+        self.area
+    }
+}
+
+#[derive(Clone)]
+pub struct StatefulAscii {
+    source: ImageSource,
+    font_size: FontSize,
+    current: Ascii,
+    hash: u64,
+    last_error: Option<EncodeFailure>,
+}
+
+impl StatefulAscii {
+    pub fn new(source: ImageSource, font_size: FontSize, options: AsciiOptions) -> StatefulAscii {
+        StatefulAscii {
+            source,
+            font_size,
+            current: Ascii {
+                options,
+                ..Ascii::default()
+            },
+            hash: u64::default(),
+            last_error: None,
+        }
+    }
+}
+
+impl ProtocolTrait for StatefulAscii {
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        Ascii::render(&mut self.current, area, buf);
+    }
+
+    fn area(&self) -> Rect {
+        self.current.area
+    }
+}
+
+impl StatefulProtocolTrait for StatefulAscii {
+    fn background_color(&self) -> Rgba<u8> {
+        self.source.background_color
+    }
+    fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect> {
+        resize.needs_resize(
+            &self.source,
+            self.font_size,
+            self.current.area,
+            area,
+            self.source.hash != self.hash,
+        )
+    }
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
+        if area.width == 0 || area.height == 0 {
+            return Ok(());
+        }
+
+        let img = resize.resize(&self.source, self.font_size, area, background_color);
+        self.current.data = encode(&img, area, &self.current.options);
+        self.current.area = area;
+        self.hash = self.source.hash;
+        Ok(())
+    }
+    fn last_error(&self) -> Option<&EncodeFailure> {
+        self.last_error.as_ref()
+    }
+    fn last_error_mut(&mut self) -> &mut Option<EncodeFailure> {
+        &mut self.last_error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use ratatui::{layout::Rect, style::Color};
+
+    use super::{encode, AsciiOptions};
+    use crate::protocol::color::ColorDepth;
+
+    #[test]
+    fn test_encode_ramp_and_edges() {
+        // Black left half, white right half.
+        let img = DynamicImage::from(RgbaImage::from_fn(4, 3, |x, _| {
+            if x < 2 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
+            }
+        }));
+        let rect = Rect::new(0, 0, 4, 3);
+        let symbols = |options| -> String {
+            encode(&img, rect, &options)
+                .iter()
+                .take(4)
+                .map(|cell| cell.symbol)
+                .collect()
+        };
+        assert_eq!(symbols(AsciiOptions::default()), "  @@");
+        assert_eq!(
+            symbols(AsciiOptions {
+                edges: true,
+                ..AsciiOptions::default()
+            }),
+            " ||@"
+        );
+
+        let colored = encode(
+            &img,
+            rect,
+            &AsciiOptions {
+                color: Some(ColorDepth::Ansi16),
+                ..AsciiOptions::default()
+            },
+        );
+        assert_eq!(colored[3].fg, Some(Color::White));
+    }
+}
@@ -0,0 +1,293 @@
+//! Block-mosaic protocol implementations.
+//! Uses the unicode quadrant, sextant or octant characters combined with foreground and background
+//! color, like [super::halfblocks] but with a higher resolution of 2x2, 2x3 or 2x4 pixels per cell.
+//! For each cell, the split of its pixels into two colors with the least error is picked.
+//!
+//! Sextants are part of the "Symbols for Legacy Computing" block of Unicode 13, octants of
+//! Unicode 16. The font must support them, but no terminal graphics support is needed.
+use image::{imageops::FilterType, DynamicImage, Rgba};
+use ratatui::{buffer::Buffer, layout::Rect, style::Color};
+
+use super::{EncodeFailure, ProtocolTrait, StatefulProtocolTrait};
+use crate::{FontSize, ImageSource, Resize, Result};
+
+/// The symbols are listed by the bit pattern of their foreground pixels, in row-major order.
+const QUADRANTS: [char; 16] = [
+    ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+];
+const SEXTANTS: [char; 64] = [
+    ' ', '🬀', '🬁', '🬂', '🬃', '🬄', '🬅', '🬆', '🬇', '🬈', '🬉', '🬊', '🬋', '🬌', '🬍', '🬎', '🬏', '🬐', '🬑',
+    '🬒', '🬓', '▌', '🬔', '🬕', '🬖', '🬗', '🬘', '🬙', '🬚', '🬛', '🬜', '🬝', '🬞', '🬟', '🬠', '🬡', '🬢', '🬣',
+    '🬤', '🬥', '🬦', '🬧', '▐', '🬨', '🬩', '🬪', '🬫', '🬬', '🬭', '🬮', '🬯', '🬰', '🬱', '🬲', '🬳', '🬴', '🬵',
+    '🬶', '🬷', '🬸', '🬹', '🬺', '🬻', '█',
+];
+const OCTANTS: [char; 256] = [
+    ' ', '𜺨', '𜺫', '🮂', '𜴀', '▘', '𜴁', '𜴂', '𜴃', '𜴄', '▝', '𜴅', '𜴆', '𜴇', '𜴈', '▀', '𜴉', '𜴊', '𜴋',
+    '𜴌', '🯦', '𜴍', '𜴎', '𜴏', '𜴐', '𜴑', '𜴒', '𜴓', '𜴔', '𜴕', '𜴖', '𜴗', '𜴘', '𜴙', '𜴚', '𜴛', '𜴜', '𜴝',
+    '𜴞', '𜴟', '🯧', '𜴠', '𜴡', '𜴢', '𜴣', '𜴤', '𜴥', '𜴦', '𜴧', '𜴨', '𜴩', '𜴪', '𜴫', '𜴬', '𜴭', '𜴮', '𜴯',
+    '𜴰', '𜴱', '𜴲', '𜴳', '𜴴', '𜴵', '🮅', '𜺣', '𜴶', '𜴷', '𜴸', '𜴹', '𜴺', '𜴻', '𜴼', '𜴽', '𜴾', '𜴿', '𜵀',
+    '𜵁', '𜵂', '𜵃', '𜵄', '▖', '𜵅', '𜵆', '𜵇', '𜵈', '▌', '𜵉', '𜵊', '𜵋', '𜵌', '▞', '𜵍', '𜵎', '𜵏', '𜵐',
+    '▛', '𜵑', '𜵒', '𜵓', '𜵔', '𜵕', '𜵖', '𜵗', '𜵘', '𜵙', '𜵚', '𜵛', '𜵜', '𜵝', '𜵞', '𜵟', '𜵠', '𜵡', '𜵢',
+    '𜵣', '𜵤', '𜵥', '𜵦', '𜵧', '𜵨', '𜵩', '𜵪', '𜵫', '𜵬', '𜵭', '𜵮', '𜵯', '𜵰', '𜺠', '𜵱', '𜵲', '𜵳', '𜵴',
+    '𜵵', '𜵶', '𜵷', '𜵸', '𜵹', '𜵺', '𜵻', '𜵼', '𜵽', '𜵾', '𜵿', '𜶀', '𜶁', '𜶂', '𜶃', '𜶄', '𜶅', '𜶆', '𜶇',
+    '𜶈', '𜶉', '𜶊', '𜶋', '𜶌', '𜶍', '𜶎', '𜶏', '▗', '𜶐', '𜶑', '𜶒', '𜶓', '▚', '𜶔', '𜶕', '𜶖', '𜶗', '▐',
+    '𜶘', '𜶙', '𜶚', '𜶛', '▜', '𜶜', '𜶝', '𜶞', '𜶟', '𜶠', '𜶡', '𜶢', '𜶣', '𜶤', '𜶥', '𜶦', '𜶧', '𜶨', '𜶩',
+    '𜶪', '𜶫', '▂', '𜶬', '𜶭', '𜶮', '𜶯', '𜶰', '𜶱', '𜶲', '𜶳', '𜶴', '𜶵', '𜶶', '𜶷', '𜶸', '𜶹', '𜶺', '𜶻',
+    '𜶼', '𜶽', '𜶾', '𜶿', '𜷀', '𜷁', '𜷂', '𜷃', '𜷄', '𜷅', '𜷆', '𜷇', '𜷈', '𜷉', '𜷊', '𜷋', '𜷌', '𜷍', '𜷎',
+    '𜷏', '𜷐', '𜷑', '𜷒', '𜷓', '𜷔', '𜷕', '𜷖', '𜷗', '𜷘', '𜷙', '𜷚', '▄', '𜷛', '𜷜', '𜷝', '𜷞', '▙', '𜷟',
+    '𜷠', '𜷡', '𜷢', '▟', '𜷣', '▆', '𜷤', '𜷥', '█',
+];
+
+/// The character set, which determines the pixels per cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum BlockKind {
+    /// 2x2 pixels per cell, supported by practically all fonts.
+    #[default]
+    Quadrants,
+    /// 2x3 pixels per cell, Unicode 13.
+    Sextants,
+    /// 2x4 pixels per cell, Unicode 16.
+    Octants,
+}
+
+impl BlockKind {
+    fn rows(self) -> u32 {
+        match self {
+            BlockKind::Quadrants => 2,
+            BlockKind::Sextants => 3,
+            BlockKind::Octants => 4,
+        }
+    }
+    fn symbol(self, mask: usize) -> char {
+        match self {
+            BlockKind::Quadrants => QUADRANTS[mask],
+            BlockKind::Sextants => SEXTANTS[mask],
+            BlockKind::Octants => OCTANTS[mask],
+        }
+    }
+}
+
+// Fixed Blocks protocol
+#[derive(Clone, Default)]
+pub struct Blocks {
+    data: Vec<Block>,
+    area: Rect,
+    kind: BlockKind,
+}
+
+#[derive(Clone, Debug)]
+struct Block {
+    symbol: char,
+    fg: Color,
+    bg: Color,
+}
+
+impl Blocks {
+    /// Create a Blocks from an image.
+    ///
+    /// The "resolution" is determined by the font size of the terminal, see
+    /// [super::halfblocks::Halfblocks::new].
+    pub fn new(image: DynamicImage, area: Rect, kind: BlockKind) -> Result<Self> {
+        let data = encode(&image, area, kind);
+        Ok(Self { data, area, kind })
+    }
+}
+
+fn encode(img: &DynamicImage, rect: Rect, kind: BlockKind) -> Vec<Block> {
+    let rows = kind.rows();
+    let img = img.resize_exact(
+        rect.width as u32 * 2,
+        rect.height as u32 * rows,
+        FilterType::Triangle,
+    );
+    let img = img.to_rgb8();
+
+    let mut data = Vec::with_capacity((rect.width * rect.height) as usize);
+    let mut pixels = Vec::with_capacity(2 * rows as usize);
+    for y in 0..rect.height as u32 {
+        for x in 0..rect.width as u32 {
+            pixels.clear();
+            for py in 0..rows {
+                for px in 0..2 {
+                    let pixel = img.get_pixel(x * 2 + px, y * rows + py);
+                    pixels.push([pixel[0] as i32, pixel[1] as i32, pixel[2] as i32]);
+                }
+            }
+            let (mask, fg, bg) = best_split(&pixels);
+            data.push(Block {
+                symbol: kind.symbol(mask),
+                fg,
+                bg,
+            });
+        }
+    }
+    data
+}
+
+/// Find the split of the pixels into a foreground and a background group, that minimizes the
+/// squared error to the mean color of each group.
+///
+/// Returns the foreground bit pattern and the two mean colors.
+fn best_split(pixels: &[[i32; 3]]) -> (usize, Color, Color) {
+    let n = pixels.len();
+    let total = pixels.iter().fold([0; 3], |acc, p| {
+        [acc[0] + p[0], acc[1] + p[1], acc[2] + p[2]]
+    });
+
+    // The squared error of a split is the sum of squares minus |sum|²/count of each group, so
+    // maximizing the latter minimizes the error. The last pixel is always background, the inverse
+    // split is the same. Only a strictly better split than no split at all is taken.
+    let mut best = (0, norm(total) / n as f64);
+    for mask in 1..(1usize << (n - 1)) {
+        let mut fg_sum = [0; 3];
+        let mut fg_count = 0;
+        for (i, pixel) in pixels.iter().enumerate() {
+            if mask & (1 << i) != 0 {
+                fg_count += 1;
+                for c in 0..3 {
+                    fg_sum[c] += pixel[c];
+                }
+            }
+        }
+        let bg_sum = [0, 1, 2].map(|c| total[c] - fg_sum[c]);
+        let score = norm(fg_sum) / fg_count as f64 + norm(bg_sum) / (n - fg_count) as f64;
+        if score > best.1 {
+            best = (mask, score);
+        }
+    }
+
+    let mask = best.0;
+    let mean = |fg: bool| {
+        let mut sum = [0; 3];
+        let mut count = 0;
+        for (i, pixel) in pixels.iter().enumerate() {
+            if (mask & (1 << i) != 0) == fg {
+                count += 1;
+                for c in 0..3 {
+                    sum[c] += pixel[c];
+                }
+            }
+        }
+        let [r, g, b] = sum.map(|s| (s + count / 2) / count.max(1));
+        Color::Rgb(r as u8, g as u8, b as u8)
+    };
+    (mask, mean(true), mean(false))
+}
+
+fn norm(sum: [i32; 3]) -> f64 {
+    sum.iter().map(|s| (*s as f64).powi(2)).sum()
+}
+
+impl ProtocolTrait for Blocks {
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        for (i, block) in self.data.iter().enumerate() {
+            let x = i as u16 % self.area.width;
+            let y = i as u16 / self.area.width;
+            if x >= area.width || y >= area.height {
+                continue;
+            }
+
+            buf.cell_mut((area.x + x, area.y + y)).map(|cell| {
+                cell.set_fg(block.fg)
+                    .set_bg(block.bg)
+                    .set_char(block.symbol)
+            });
+        }
+    }
+    fn area(&self) -> Rect {
+        self.area
+    }
+}
+
+#[derive(Clone)]
+pub struct StatefulBlocks {
+    source: ImageSource,
+    font_size: FontSize,
+    current: Blocks,
+    hash: u64,
+    last_error: Option<EncodeFailure>,
+}
+
+impl StatefulBlocks {
+    pub fn new(source: ImageSource, font_size: FontSize, kind: BlockKind) -> StatefulBlocks {
+        StatefulBlocks {
+            source,
+            font_size,
+            current: Blocks {
+                kind,
+                ..Blocks::default()
+            },
+            hash: u64::default(),
+            last_error: None,
+        }
+    }
+}
+
+impl ProtocolTrait for StatefulBlocks {
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        Blocks::render(&mut self.current, area, buf);
+    }
+
+    fn area(&self) -> Rect {
+        self.current.area
+    }
+}
+
+impl StatefulProtocolTrait for StatefulBlocks {
+    fn background_color(&self) -> Rgba<u8> {
+        self.source.background_color
+    }
+    fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect> {
+        resize.needs_resize(
+            &self.source,
+            self.font_size,
+            self.current.area,
+            area,
+            self.source.hash != self.hash,
+        )
+    }
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
+        if area.width == 0 || area.height == 0 {
+            return Ok(());
+        }
+
+        let img = resize.resize(&self.source, self.font_size, area, background_color);
+        let kind = self.current.kind;
+        let data = encode(&img, area, kind);
+        self.current = Blocks { data, area, kind };
+        self.hash = self.source.hash;
+        Ok(())
+    }
+    fn last_error(&self) -> Option<&EncodeFailure> {
+        self.last_error.as_ref()
+    }
+    fn last_error_mut(&mut self) -> &mut Option<EncodeFailure> {
+        &mut self.last_error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::style::Color;
+
+    use super::best_split;
+
+    #[test]
+    fn test_best_split() {
+        let (r, b) = ([255, 0, 0], [0, 0, 255]);
+        // Top-left and bottom-right red, the last pixel is always background so this is `▞`.
+        let (mask, fg, bg) = best_split(&[r, b, b, r]);
+        assert_eq!(mask, 0b0110);
+        assert_eq!(fg, Color::Rgb(0, 0, 255));
+        assert_eq!(bg, Color::Rgb(255, 0, 0));
+
+        let (mask, _, bg) = best_split(&[b; 8]);
+        assert_eq!(mask, 0);
+        assert_eq!(bg, Color::Rgb(0, 0, 255));
+    }
+}
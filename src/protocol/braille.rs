@@ -0,0 +1,276 @@
+//! Braille protocol implementations.
+//! Uses the unicode braille patterns `⠀`-`⣿`, with 2x4 dots per cell. Each dot is either lit or
+//! not, so this works best for line art, plots, and QR codes. Should work in all terminals.
+//!
+//! The dots are lit by thresholding the luminance, optionally with dithering. Each cell can be
+//! colored with the average color of its lit pixels.
+use image::{imageops::FilterType, DynamicImage, Rgba};
+use ratatui::{buffer::Buffer, layout::Rect, style::Color};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::{EncodeFailure, ProtocolTrait, StatefulProtocolTrait};
+use crate::{FontSize, ImageSource, Resize, Result};
+
+/// The bit of the braille pattern for each dot, by `[y][x]` in the cell.
+const DOTS: [[u8; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+/// Options for the [Braille] protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct BrailleOptions {
+    /// Pixels with a luminance above this light up their dot.
+    pub threshold: u8,
+    /// Floyd–Steinberg dithering of the luminance, for photos and gradients.
+    pub dither: bool,
+    /// Color each cell with the average color of its lit pixels. Otherwise the foreground color
+    /// is not set.
+    pub color: bool,
+    /// Light up dark pixels instead, for dark drawings on a light background.
+    pub invert: bool,
+}
+
+impl Default for BrailleOptions {
+    fn default() -> Self {
+        BrailleOptions {
+            threshold: 128,
+            dither: true,
+            color: true,
+            invert: false,
+        }
+    }
+}
+
+// Fixed Braille protocol
+#[derive(Clone, Default)]
+pub struct Braille {
+    data: Vec<BrailleCell>,
+    area: Rect,
+    options: BrailleOptions,
+}
+
+#[derive(Clone, Debug)]
+struct BrailleCell {
+    symbol: char,
+    fg: Option<Color>,
+}
+
+impl Braille {
+    /// Create a Braille from an image.
+    ///
+    /// The "resolution" is determined by the font size of the terminal, see
+    /// [super::halfblocks::Halfblocks::new].
+    pub fn new(image: DynamicImage, area: Rect, options: BrailleOptions) -> Result<Self> {
+        let data = encode(&image, area, &options);
+        Ok(Self {
+            data,
+            area,
+            options,
+        })
+    }
+}
+
+fn encode(img: &DynamicImage, rect: Rect, options: &BrailleOptions) -> Vec<BrailleCell> {
+    let (width, height) = (rect.width as usize * 2, rect.height as usize * 4);
+    let img = img
+        .resize_exact(width as u32, height as u32, FilterType::Triangle)
+        .to_rgba8();
+
+    // Luminance, premultiplied by alpha so that transparent pixels are dark.
+    let mut luma: Vec<f32> = img
+        .pixels()
+        .map(|p| {
+            let l = (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32) * p[3] as f32
+                / 255.0;
+            if options.invert && p[3] > 0 {
+                255.0 - l
+            } else {
+                l
+            }
+        })
+        .collect();
+
+    let threshold = options.threshold as f32;
+    let mut lit = vec![false; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            lit[i] = luma[i] > threshold;
+            if !options.dither {
+                continue;
+            }
+            let error = luma[i] - if lit[i] { 255.0 } else { 0.0 };
+            for (dx, dy, weight) in [
+                (1, 0, 7.0 / 16.0),
+                (-1, 1, 3.0 / 16.0),
+                (0, 1, 5.0 / 16.0),
+                (1, 1, 1.0 / 16.0),
+            ] {
+                let (nx, ny) = (x as isize + dx, y + dy);
+                if nx >= 0 && (nx as usize) < width && ny < height {
+                    luma[ny * width + nx as usize] += error * weight;
+                }
+            }
+        }
+    }
+
+    let mut data = Vec::with_capacity(rect.width as usize * rect.height as usize);
+    for cy in 0..rect.height as usize {
+        for cx in 0..rect.width as usize {
+            let mut bits = 0;
+            let mut sum = [0u32; 3];
+            let mut count = 0;
+            for (dy, row) in DOTS.iter().enumerate() {
+                for (dx, dot) in row.iter().enumerate() {
+                    let (x, y) = (cx * 2 + dx, cy * 4 + dy);
+                    if lit[y * width + x] {
+                        bits |= dot;
+                        let pixel = img.get_pixel(x as u32, y as u32);
+                        for c in 0..3 {
+                            sum[c] += pixel[c] as u32;
+                        }
+                        count += 1;
+                    }
+                }
+            }
+            let fg = (options.color && count > 0).then(|| {
+                let [r, g, b] = sum.map(|s| ((s + count / 2) / count) as u8);
+                Color::Rgb(r, g, b)
+            });
+            data.push(BrailleCell {
+                symbol: char::from_u32(0x2800 + bits as u32).unwrap_or(' '),
+                fg,
+            });
+        }
+    }
+    data
+}
+
+impl ProtocolTrait for Braille {
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        for (i, braille) in self.data.iter().enumerate() {
+            let x = i as u16 % self.area.width;
+            let y = i as u16 / self.area.width;
+            if x >= area.width || y >= area.height {
+                continue;
+            }
+
+            if let Some(cell) = buf.cell_mut((area.x + x, area.y + y)) {
+                cell.set_char(braille.symbol);
+                if let Some(fg) = braille.fg {
+                    cell.set_fg(fg);
+                }
+            }
+        }
+    }
+    fn area(&self) -> Rect {
+        self.area
+    }
+}
+
+#[derive(Clone)]
+pub struct StatefulBraille {
+    source: ImageSource,
+    font_size: FontSize,
+    current: Braille,
+    hash: u64,
+    last_error: Option<EncodeFailure>,
+}
+
+impl StatefulBraille {
+    pub fn new(
+        source: ImageSource,
+        font_size: FontSize,
+        options: BrailleOptions,
+    ) -> StatefulBraille {
+        StatefulBraille {
+            source,
+            font_size,
+            current: Braille {
+                options,
+                ..Braille::default()
+            },
+            hash: u64::default(),
+            last_error: None,
+        }
+    }
+}
+
+impl ProtocolTrait for StatefulBraille {
+    fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        Braille::render(&mut self.current, area, buf);
+    }
+
+    fn area(&self) -> Rect {
+        self.current.area
+    }
+}
+
+impl StatefulProtocolTrait for StatefulBraille {
+    fn background_color(&self) -> Rgba<u8> {
+        self.source.background_color
+    }
+    fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect> {
+        resize.needs_resize(
+            &self.source,
+            self.font_size,
+            self.current.area,
+            area,
+            self.source.hash != self.hash,
+        )
+    }
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
+        if area.width == 0 || area.height == 0 {
+            return Ok(());
+        }
+
+        let img = resize.resize(&self.source, self.font_size, area, background_color);
+        let options = self.current.options;
+        let data = encode(&img, area, &options);
+        self.current = Braille {
+            data,
+            area,
+            options,
+        };
+        self.hash = self.source.hash;
+        Ok(())
+    }
+    fn last_error(&self) -> Option<&EncodeFailure> {
+        self.last_error.as_ref()
+    }
+    fn last_error_mut(&mut self) -> &mut Option<EncodeFailure> {
+        &mut self.last_error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use ratatui::{layout::Rect, style::Color};
+
+    use super::{encode, BrailleOptions};
+
+    #[test]
+    fn test_encode_dots() {
+        // Left column lit white, right column black.
+        let img = RgbaImage::from_fn(2, 4, |x, _| {
+            if x == 0 {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        let options = BrailleOptions {
+            dither: false,
+            ..BrailleOptions::default()
+        };
+        let data = encode(&DynamicImage::from(img), Rect::new(0, 0, 1, 1), &options);
+        assert_eq!(data[0].symbol, '⡇');
+        assert_eq!(data[0].fg, Some(Color::Rgb(255, 255, 255)));
+    }
+}
@@ -0,0 +1,163 @@
+//! Terminal color depths, and quantization of RGB colors to them.
+use image::RgbImage;
+use ratatui::style::Color;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// How many colors the terminal can show, for the text based protocols.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum ColorDepth {
+    /// The 16 ANSI colors, whose actual values depend on the terminal theme.
+    Ansi16,
+    /// The xterm 256 color palette.
+    Indexed256,
+    /// 24 bit RGB colors.
+    #[default]
+    TrueColor,
+}
+
+/// The ANSI colors and their default xterm values.
+const ANSI16: [(Color, [u8; 3]); 16] = [
+    (Color::Black, [0, 0, 0]),
+    (Color::Red, [205, 0, 0]),
+    (Color::Green, [0, 205, 0]),
+    (Color::Yellow, [205, 205, 0]),
+    (Color::Blue, [0, 0, 238]),
+    (Color::Magenta, [205, 0, 205]),
+    (Color::Cyan, [0, 205, 205]),
+    (Color::Gray, [229, 229, 229]),
+    (Color::DarkGray, [127, 127, 127]),
+    (Color::LightRed, [255, 0, 0]),
+    (Color::LightGreen, [0, 255, 0]),
+    (Color::LightYellow, [255, 255, 0]),
+    (Color::LightBlue, [92, 92, 255]),
+    (Color::LightMagenta, [255, 0, 255]),
+    (Color::LightCyan, [0, 255, 255]),
+    (Color::White, [255, 255, 255]),
+];
+
+/// The levels of each channel in the xterm 6x6x6 color cube.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+impl ColorDepth {
+    /// The nearest color that this depth can show, and its RGB value.
+    pub fn quantize(self, rgb: [u8; 3]) -> (Color, [u8; 3]) {
+        match self {
+            ColorDepth::TrueColor => (Color::Rgb(rgb[0], rgb[1], rgb[2]), rgb),
+            ColorDepth::Indexed256 => {
+                let cube = rgb.map(nearest_cube_level);
+                let cube_rgb = cube.map(|i| CUBE_LEVELS[i]);
+                let cube_index = 16 + 36 * cube[0] + 6 * cube[1] + cube[2];
+
+                let mean = rgb.iter().map(|c| *c as usize).sum::<usize>() / 3;
+                let gray = (mean.saturating_sub(3) / 10).min(23);
+                let gray_value = 8 + 10 * gray as u8;
+                let gray_rgb = [gray_value; 3];
+
+                if distance(rgb, gray_rgb) < distance(rgb, cube_rgb) {
+                    (Color::Indexed(232 + gray as u8), gray_rgb)
+                } else {
+                    (Color::Indexed(cube_index as u8), cube_rgb)
+                }
+            }
+            ColorDepth::Ansi16 => ANSI16
+                .iter()
+                .copied()
+                .min_by_key(|(_, value)| distance(rgb, *value))
+                .unwrap_or(ANSI16[0]),
+        }
+    }
+}
+
+/// Quantize all pixels to the color depth, with Floyd–Steinberg error diffusion.
+///
+/// Returns the colors in row-major order.
+pub(crate) fn dither(img: &RgbImage, depth: ColorDepth) -> Vec<Color> {
+    if depth == ColorDepth::TrueColor {
+        return img.pixels().map(|p| Color::Rgb(p[0], p[1], p[2])).collect();
+    }
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let mut pixels: Vec<[f32; 3]> = img.pixels().map(|p| p.0.map(f32::from)).collect();
+    let mut colors = Vec::with_capacity(pixels.len());
+    for i in 0..pixels.len() {
+        let (x, y) = (i % width, i / width);
+        let (color, quantized) =
+            depth.quantize(pixels[i].map(|c| c.round().clamp(0.0, 255.0) as u8));
+        colors.push(color);
+        let error: [f32; 3] = std::array::from_fn(|c| pixels[i][c] - quantized[c] as f32);
+        for (dx, dy, weight) in [
+            (1, 0, 7.0 / 16.0),
+            (-1, 1, 3.0 / 16.0),
+            (0, 1, 5.0 / 16.0),
+            (1, 1, 1.0 / 16.0),
+        ] {
+            let (nx, ny) = (x as isize + dx, y + dy);
+            if nx >= 0 && (nx as usize) < width && ny < height {
+                let neighbour = &mut pixels[ny * width + nx as usize];
+                for c in 0..3 {
+                    neighbour[c] += error[c] * weight;
+                }
+            }
+        }
+    }
+    colors
+}
+
+fn nearest_cube_level(c: u8) -> usize {
+    match c {
+        0..=47 => 0,
+        48..=114 => 1,
+        _ => ((c - 35) / 40) as usize,
+    }
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| (*a as i32 - *b as i32).pow(2) as u32)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+    use ratatui::style::Color;
+
+    use super::{dither, ColorDepth};
+
+    #[test]
+    fn test_quantize() {
+        assert_eq!(
+            ColorDepth::Indexed256.quantize([255, 0, 0]),
+            (Color::Indexed(196), [255, 0, 0])
+        );
+        assert_eq!(
+            ColorDepth::Indexed256.quantize([128, 128, 128]),
+            (Color::Indexed(244), [128, 128, 128])
+        );
+        assert_eq!(
+            ColorDepth::Indexed256.quantize([100, 140, 180]),
+            (Color::Indexed(67), [95, 135, 175])
+        );
+        assert_eq!(
+            ColorDepth::Ansi16.quantize([10, 200, 20]),
+            (Color::Green, [0, 205, 0])
+        );
+    }
+
+    #[test]
+    fn test_dither() {
+        // Mid gray between black and dark gray, in the 16 ANSI colors.
+        let img = RgbImage::from_pixel(4, 4, Rgb([64, 64, 64]));
+        let colors = dither(&img, ColorDepth::Ansi16);
+        let dark = colors.iter().filter(|c| **c == Color::DarkGray).count();
+        let black = colors.iter().filter(|c| **c == Color::Black).count();
+        assert_eq!(dark + black, 16);
+        assert!((6..=10).contains(&dark), "{colors:?}");
+    }
+}
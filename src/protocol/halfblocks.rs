@@ -1,10 +1,16 @@
 //! Halfblocks protocol implementations.
 //! Uses the unicode character `▀` combined with foreground and background color. Assumes that the
 //! font aspect ratio is roughly 1:2. Should work in all terminals.
-use image::{imageops::FilterType, DynamicImage, Rgba};
+//!
+//! On terminals without truecolor, the colors are dithered to the xterm 256 or the 16 ANSI colors,
+//! see [ColorDepth].
+use image::{imageops::FilterType, DynamicImage, Rgb, RgbImage, Rgba};
 use ratatui::{buffer::Buffer, layout::Rect, style::Color};
 
-use super::{ProtocolTrait, StatefulProtocolTrait};
+use super::{
+    color::{dither, ColorDepth},
+    EncodeFailure, ProtocolTrait, StatefulProtocolTrait,
+};
 use crate::{FontSize, ImageSource, Resize, Result};
 
 // Fixed Halfblocks protocol
@@ -12,6 +18,7 @@ use crate::{FontSize, ImageSource, Resize, Result};
 pub struct Halfblocks {
     data: Vec<HalfBlock>,
     area: Rect,
+    color_depth: ColorDepth,
 }
 
 #[derive(Clone, Debug)]
@@ -28,13 +35,24 @@ impl Halfblocks {
     /// the image could be resized in relation to the font size beforehand.
     /// Also note that the font-size is probably just some arbitrary size with a 1:2 ratio when the
     /// protocol is Halfblocks, and not the actual font size of the terminal.
-    pub fn new(image: DynamicImage, area: Rect) -> Result<Self> {
-        let data = encode(&image, area);
-        Ok(Self { data, area })
+    ///
+    /// Transparent pixels keep their color, as there is no background color to blend against.
+    pub fn new(image: DynamicImage, area: Rect, color_depth: ColorDepth) -> Result<Self> {
+        let data = encode(&image, area, color_depth, Rgba([0, 0, 0, 0]));
+        Ok(Self {
+            data,
+            area,
+            color_depth,
+        })
     }
 }
 
-fn encode(img: &DynamicImage, rect: Rect) -> Vec<HalfBlock> {
+fn encode(
+    img: &DynamicImage,
+    rect: Rect,
+    color_depth: ColorDepth,
+    background_color: Rgba<u8>,
+) -> Vec<HalfBlock> {
     let img = img.resize_exact(
         rect.width as u32,
         (rect.height * 2) as u32,
@@ -49,19 +67,37 @@ fn encode(img: &DynamicImage, rect: Rect) -> Vec<HalfBlock> {
         (rect.width * rect.height) as usize
     ];
 
-    for (y, row) in img.to_rgb8().rows().enumerate() {
-        for (x, pixel) in row.enumerate() {
-            let position = x + (rect.width as usize) * (y / 2);
-            if y % 2 == 0 {
-                data[position].upper = Color::Rgb(pixel[0], pixel[1], pixel[2]);
-            } else {
-                data[position].lower = Color::Rgb(pixel[0], pixel[1], pixel[2]);
-            }
+    let width = rect.width as usize;
+    let img = blend(&img, background_color);
+    for (i, color) in dither(&img, color_depth).into_iter().enumerate() {
+        let (x, y) = (i % width, i / width);
+        let position = x + width * (y / 2);
+        if y % 2 == 0 {
+            data[position].upper = color;
+        } else {
+            data[position].lower = color;
         }
     }
     data
 }
 
+/// Blend the pixels with the background color by their alpha, since a half block can't be
+/// transparent. A fully transparent background color leaves the pixels' colors as they are.
+fn blend(img: &DynamicImage, background_color: Rgba<u8>) -> RgbImage {
+    let Rgba([bg_r, bg_g, bg_b, bg_a]) = background_color;
+    if bg_a == 0 {
+        return img.to_rgb8();
+    }
+    let img = img.to_rgba8();
+    RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *img.get_pixel(x, y);
+        let mix = |color: u8, bg: u8| {
+            ((color as u16 * a as u16 + bg as u16 * (255 - a as u16)) / 255) as u8
+        };
+        Rgb([mix(r, bg_r), mix(g, bg_g), mix(b, bg_b)])
+    })
+}
+
 impl ProtocolTrait for Halfblocks {
     fn render(&mut self, area: Rect, buf: &mut Buffer) {
         for (i, hb) in self.data.iter().enumerate() {
@@ -86,15 +122,24 @@ pub struct StatefulHalfblocks {
     font_size: FontSize,
     current: Halfblocks,
     hash: u64,
+    last_error: Option<EncodeFailure>,
 }
 
 impl StatefulHalfblocks {
-    pub fn new(source: ImageSource, font_size: FontSize) -> StatefulHalfblocks {
+    pub fn new(
+        source: ImageSource,
+        font_size: FontSize,
+        color_depth: ColorDepth,
+    ) -> StatefulHalfblocks {
         StatefulHalfblocks {
             source,
             font_size,
-            current: Halfblocks::default(),
+            current: Halfblocks {
+                color_depth,
+                ..Halfblocks::default()
+            },
             hash: u64::default(),
+            last_error: None,
         }
     }
 }
@@ -121,15 +166,53 @@ impl StatefulProtocolTrait for StatefulHalfblocks {
             self.source.hash != self.hash,
         )
     }
-    fn resize_encode(&mut self, resize: &Resize, background_color: Rgba<u8>, area: Rect) {
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
         if area.width == 0 || area.height == 0 {
-            return;
+            return Ok(());
         }
 
         let img = resize.resize(&self.source, self.font_size, area, background_color);
-        let data = encode(&img, area);
-        let current = Halfblocks { data, area };
+        let color_depth = self.current.color_depth;
+        let data = encode(&img, area, color_depth, background_color);
+        let current = Halfblocks {
+            data,
+            area,
+            color_depth,
+        };
         self.current = current;
         self.hash = self.source.hash;
+        Ok(())
+    }
+    fn last_error(&self) -> Option<&EncodeFailure> {
+        self.last_error.as_ref()
+    }
+    fn last_error_mut(&mut self) -> &mut Option<EncodeFailure> {
+        &mut self.last_error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgb, Rgba, RgbaImage};
+
+    use super::blend;
+
+    #[test]
+    fn test_blend() {
+        let mut img = RgbaImage::from_pixel(3, 1, Rgba([255, 255, 255, 255]));
+        img.put_pixel(1, 0, Rgba([255, 255, 255, 0]));
+        img.put_pixel(2, 0, Rgba([255, 255, 255, 128]));
+        let blended = blend(&DynamicImage::from(img.clone()), Rgba([0, 0, 100, 255]));
+        assert_eq!(blended.get_pixel(0, 0), &Rgb([255, 255, 255]));
+        assert_eq!(blended.get_pixel(1, 0), &Rgb([0, 0, 100]));
+        assert_eq!(blended.get_pixel(2, 0), &Rgb([128, 128, 177]));
+
+        let unblended = blend(&DynamicImage::from(img), Rgba([0, 0, 0, 0]));
+        assert_eq!(unblended.get_pixel(1, 0), &Rgb([255, 255, 255]));
     }
 }
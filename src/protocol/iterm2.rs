@@ -1,61 +1,171 @@
 //! ITerm2 protocol implementation.
 use base64::{engine::general_purpose, Engine};
-use image::{DynamicImage, Rgba};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, Rgba};
 use ratatui::{buffer::Buffer, layout::Rect};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::{cmp::min, format, io::Cursor};
 
-use crate::{errors, picker::cap_parser::Parser, FontSize, ImageSource, Resize, Result};
+use crate::{picker::cap_parser::Parser, FontSize, ImageSource, Resize, Result};
 
-use super::{ProtocolTrait, StatefulProtocolTrait};
+use super::{erase_area, underlay_background, EncodeFailure, ProtocolTrait, StatefulProtocolTrait};
+
+/// Length of the base64 payload of each `FilePart`, a multiple of 4 so that every part can be
+/// decoded by itself.
+const PART_SIZE: usize = 4096;
+
+/// The file format that images are sent as.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Iterm2Encoding {
+    /// Lossless, and keeps transparency.
+    #[default]
+    Png,
+    /// Much smaller and faster to encode for photos. The quality ranges from 1 to 100, and
+    /// transparency is lost.
+    Jpeg { quality: u8 },
+    /// The original file of the image, if it was created with [ImageSource::new_encoded].
+    ///
+    /// The terminal decodes and scales the file into the area by itself, so resizing never
+    /// re-encodes. Falls back to [Iterm2Encoding::Png] for other images, and for [Resize::Crop]
+    /// which the terminal cannot do.
+    Original,
+}
+
+/// Options for the ITerm2 protocol.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct Iterm2Options {
+    /// Transmit the file in chunks with `MultipartFile`, `FilePart` and `FileEnd`, instead of one
+    /// `File` sequence.
+    ///
+    /// Supported by iTerm2 3.5 and WezTerm. Each chunk is wrapped separately inside tmux, whose
+    /// passthrough often truncates large sequences.
+    pub multipart: bool,
+    /// The file format of the payload. PNG, the default, needs the `image-defaults` feature.
+    pub encoding: Iterm2Encoding,
+    /// Encode the source image once, and let the terminal scale it into the area, sized in
+    /// cells with `preserveAspectRatio`.
+    ///
+    /// Resizing then only changes the size arguments and reuses the payload, for [Resize::Fit]
+    /// and [Resize::Scale]. The terminal scales with its own filter instead of the one of the
+    /// [Resize]. Only for [StatefulIterm2].
+    pub terminal_scaling: bool,
+}
 
 #[derive(Clone, Default)]
 pub struct Iterm2 {
     pub data: String,
     pub area: Rect,
     pub is_tmux: bool,
+    pub options: Iterm2Options,
 }
 
 impl Iterm2 {
-    pub fn new(image: DynamicImage, area: Rect, is_tmux: bool) -> Result<Self> {
-        let data = encode(&image, area, is_tmux)?;
+    /// Encode the image for the area. With [Iterm2Encoding::Jpeg], transparent pixels become
+    /// black.
+    pub fn new(
+        image: DynamicImage,
+        area: Rect,
+        is_tmux: bool,
+        options: Iterm2Options,
+    ) -> Result<Self> {
+        let data = encode(&image, area, is_tmux, &options, Rgba([0, 0, 0, 0]))?;
         Ok(Self {
             data,
             area,
             is_tmux,
+            options,
         })
     }
 }
 
-fn encode(img: &DynamicImage, render_area: Rect, is_tmux: bool) -> Result<String> {
-    let mut png: Vec<u8> = vec![];
-    img.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)?;
+fn encode(
+    img: &DynamicImage,
+    render_area: Rect,
+    is_tmux: bool,
+    options: &Iterm2Options,
+    background_color: Rgba<u8>,
+) -> Result<String> {
+    let payload = Payload::new(&encode_image(img, options.encoding, background_color)?);
+    let size = format!("width={}px;height={}px", img.width(), img.height());
+    Ok(write_file(&payload, &size, render_area, is_tmux, options))
+}
+
+fn encode_image(
+    img: &DynamicImage,
+    encoding: Iterm2Encoding,
+    background_color: Rgba<u8>,
+) -> Result<Vec<u8>> {
+    let mut bytes: Vec<u8> = vec![];
+    match encoding {
+        Iterm2Encoding::Png | Iterm2Encoding::Original => {
+            img.write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)?
+        }
+        Iterm2Encoding::Jpeg { quality } => {
+            // JPEG has no alpha channel, and the colors of transparent pixels are often junk, so
+            // composite onto the background made opaque.
+            let [r, g, b, _] = background_color.0;
+            let img = underlay_background(img.clone(), Rgba([r, g, b, 255]));
+            JpegEncoder::new_with_quality(&mut bytes, quality.clamp(1, 100))
+                .encode_image(&img.to_rgb8())?
+        }
+    }
+    Ok(bytes)
+}
 
-    let data = general_purpose::STANDARD.encode(&png);
+/// An encoded file, ready to be sent.
+#[derive(Clone, Default)]
+struct Payload {
+    /// Length of the file in bytes.
+    size: usize,
+    /// The file in base64.
+    data: String,
+}
+
+impl Payload {
+    fn new(bytes: &[u8]) -> Payload {
+        Payload {
+            size: bytes.len(),
+            data: general_purpose::STANDARD.encode(bytes),
+        }
+    }
+}
+
+/// The sequence that shows the file in the area, with the `width` and `height` arguments in
+/// `size`.
+fn write_file(
+    payload: &Payload,
+    size: &str,
+    render_area: Rect,
+    is_tmux: bool,
+    options: &Iterm2Options,
+) -> String {
+    let data = &payload.data;
 
     let (start, escape, end) = Parser::escape_tmux(is_tmux);
 
-    // Transparency needs explicit erasing of stale characters, or they stay behind the rendered
-    // image due to skipping of the following characters _in the buffer_.
-    // DECERA does not work in WezTerm, however ECH and and cursor CUD and CUU do.
-    // For each line, erase `width` characters, then move back and place image.
-    let width = render_area.width;
-    let height = render_area.height;
     let mut seq = String::from(start);
-    for _ in 0..height {
-        seq.push_str(&format!("{escape}[{width}X{escape}[1B").to_string());
-    }
-    seq.push_str(&format!("{escape}[{height}A").to_string());
-
-    seq.push_str(&format!(
-        "{escape}]1337;File=inline=1;size={};width={}px;height={}px;doNotMoveCursor=1:{}\x07",
-        png.len(),
-        img.width(),
-        img.height(),
-        data,
-    ));
-    seq.push_str(end);
+    seq.push_str(&erase_area(escape, render_area));
 
-    Ok::<String, errors::Errors>(seq)
+    let args = format!("inline=1;size={};{size};doNotMoveCursor=1", payload.size);
+    if options.multipart {
+        seq.push_str(&format!("{escape}]1337;MultipartFile={args}\x07{end}"));
+        for part in data.as_bytes().chunks(PART_SIZE) {
+            // Base64 is ASCII, so any chunk is valid UTF-8.
+            let part = std::str::from_utf8(part).unwrap_or_default();
+            seq.push_str(&format!("{start}{escape}]1337;FilePart={part}\x07{end}"));
+        }
+        seq.push_str(&format!("{start}{escape}]1337;FileEnd\x07"));
+    } else {
+        seq.push_str(&format!("{escape}]1337;File={args}:{data}\x07"));
+    }
+    seq.push_str(end);
+    seq
 }
 
 impl ProtocolTrait for Iterm2 {
@@ -118,19 +228,49 @@ pub struct StatefulIterm2 {
     font_size: FontSize,
     current: Iterm2,
     hash: u64,
+    last_error: Option<EncodeFailure>,
+    // The source image encoded once for terminal side scaling.
+    payload: Payload,
+    payload_hash: u64,
 }
 
 impl StatefulIterm2 {
-    pub fn new(source: ImageSource, font_size: FontSize, is_tmux: bool) -> StatefulIterm2 {
+    pub fn new(
+        source: ImageSource,
+        font_size: FontSize,
+        is_tmux: bool,
+        options: Iterm2Options,
+    ) -> StatefulIterm2 {
         StatefulIterm2 {
             source,
             font_size,
             current: Iterm2 {
                 is_tmux,
+                options,
                 ..Iterm2::default()
             },
             hash: u64::default(),
+            last_error: None,
+            payload: Payload::default(),
+            payload_hash: u64::default(),
+        }
+    }
+
+    /// The whole source image, encoded only once for all sizes.
+    fn source_payload(&mut self) -> Result<&Payload> {
+        if self.payload_hash != self.source.hash {
+            let encoding = self.current.options.encoding;
+            self.payload = match (&self.source.encoded, encoding) {
+                (Some(bytes), Iterm2Encoding::Original) => Payload::new(bytes),
+                _ => Payload::new(&encode_image(
+                    &self.source.image,
+                    encoding,
+                    self.source.background_color,
+                )?),
+            };
+            self.payload_hash = self.source.hash;
         }
+        Ok(&self.payload)
     }
 }
 
@@ -157,25 +297,219 @@ impl StatefulProtocolTrait for StatefulIterm2 {
             self.source.hash != self.hash,
         )
     }
-    fn resize_encode(&mut self, resize: &Resize, background_color: Rgba<u8>, area: Rect) {
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
         if area.width == 0 || area.height == 0 {
-            return;
+            return Ok(());
         }
 
-        let img = resize.resize(&self.source, self.font_size, area, background_color);
         let is_tmux = self.current.is_tmux;
-        match encode(&img, area, is_tmux) {
-            Ok(data) => {
-                self.current = Iterm2 {
-                    data,
-                    area,
-                    is_tmux,
-                };
-                self.hash = self.source.hash;
-            }
-            Err(_err) => {
-                // TODO: save err in struct and expose in trait?
-            }
+        let options = self.current.options;
+        let original =
+            options.encoding == Iterm2Encoding::Original && self.source.encoded.is_some();
+        let terminal_scaling = (options.terminal_scaling || original)
+            && matches!(resize, Resize::Fit(_) | Resize::Scale(_));
+        let data = if terminal_scaling {
+            // Sized in cells, the terminal scales the image into them.
+            let size = format!(
+                "width={};height={};preserveAspectRatio=1",
+                area.width, area.height
+            );
+            let payload = self.source_payload()?;
+            write_file(payload, &size, area, is_tmux, &options)
+        } else {
+            let img = resize.resize(&self.source, self.font_size, area, background_color);
+            encode(&img, area, is_tmux, &options, background_color)?
+        };
+        self.current = Iterm2 {
+            data,
+            area,
+            is_tmux,
+            options,
+        };
+        self.hash = self.source.hash;
+        Ok(())
+    }
+    fn last_error(&self) -> Option<&EncodeFailure> {
+        self.last_error.as_ref()
+    }
+    fn last_error_mut(&mut self) -> &mut Option<EncodeFailure> {
+        &mut self.last_error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose, Engine};
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::layout::Rect;
+
+    #[cfg(feature = "image-defaults")]
+    use super::PART_SIZE;
+    use super::{encode, Iterm2, Iterm2Encoding, Iterm2Options, StatefulIterm2};
+    use crate::{protocol::StatefulProtocolTrait, ImageSource, Resize};
+
+    #[test]
+    #[cfg(feature = "image-defaults")]
+    fn test_encode_multipart() {
+        // Noise, so that the PNG needs several parts.
+        let img = DynamicImage::from(ImageBuffer::from_fn(64, 64, |x, y| {
+            Rgba([(x * 7 + y * 13) as u8, (x * y) as u8, (x ^ y) as u8, 255])
+        }));
+        let options = Iterm2Options {
+            multipart: true,
+            ..Iterm2Options::default()
+        };
+        let seq = encode(
+            &img,
+            Rect::new(0, 0, 1, 1),
+            true,
+            &options,
+            Rgba([0, 0, 0, 0]),
+        )
+        .unwrap();
+
+        let sequences: Vec<&str> = seq.split("\x1bPtmux;").filter(|s| !s.is_empty()).collect();
+        assert!(sequences[0].contains("\x1b\x1b]1337;MultipartFile=inline=1;size="));
+        assert!(sequences
+            .last()
+            .unwrap()
+            .starts_with("\x1b\x1b]1337;FileEnd\x07"));
+        let parts = &sequences[1..sequences.len() - 1];
+        assert!(parts.len() > 1);
+        for part in parts {
+            assert!(part.starts_with("\x1b\x1b]1337;FilePart="));
+            assert!(part.ends_with("\x07\x1b\\"));
+            assert!(part.len() <= PART_SIZE + 32);
         }
     }
+
+    #[test]
+    fn test_encode_jpeg() {
+        let img = DynamicImage::from(ImageBuffer::from_pixel(8, 8, Rgba([200u8, 100, 50, 255])));
+        let options = Iterm2Options {
+            encoding: Iterm2Encoding::Jpeg { quality: 80 },
+            ..Iterm2Options::default()
+        };
+        let seq = encode(
+            &img,
+            Rect::new(0, 0, 1, 1),
+            false,
+            &options,
+            Rgba([0, 0, 0, 0]),
+        )
+        .unwrap();
+        let (_, data) = seq.trim_end_matches('\x07').split_once(':').unwrap();
+        let jpeg = general_purpose::STANDARD.decode(data).unwrap();
+        assert!(jpeg.starts_with(&[0xFF, 0xD8]));
+    }
+
+    #[test]
+    fn test_encode_jpeg_transparent() {
+        // Transparent white, which would stay white without compositing onto the background.
+        let img = DynamicImage::from(ImageBuffer::from_pixel(8, 8, Rgba([255u8, 255, 255, 0])));
+        let options = Iterm2Options {
+            encoding: Iterm2Encoding::Jpeg { quality: 90 },
+            ..Iterm2Options::default()
+        };
+        let iterm2 = Iterm2::new(img, Rect::new(0, 0, 1, 1), false, options).unwrap();
+        let (_, data) = iterm2
+            .data
+            .trim_end_matches('\x07')
+            .split_once(':')
+            .unwrap();
+        let jpeg = general_purpose::STANDARD.decode(data).unwrap();
+        let decoded = image::load_from_memory(&jpeg).unwrap().to_rgb8();
+        let [r, g, b] = decoded.get_pixel(4, 4).0;
+        assert!(r < 16 && g < 16 && b < 16, "{:?}", [r, g, b]);
+    }
+
+    #[test]
+    #[cfg(feature = "image-defaults")]
+    fn test_original_encoding() {
+        let mut png = Vec::new();
+        DynamicImage::from(ImageBuffer::from_pixel(40, 20, Rgba([1u8, 2, 3, 255])))
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let source = ImageSource::new_encoded(png.clone(), (10, 10), Rgba([0, 0, 0, 0])).unwrap();
+        let options = Iterm2Options {
+            encoding: Iterm2Encoding::Original,
+            ..Iterm2Options::default()
+        };
+        let mut iterm2 = StatefulIterm2::new(source, (10, 10), false, options);
+        iterm2
+            .resize_encode(
+                &Resize::Fit(None),
+                Rgba([0, 0, 0, 0]),
+                Rect::new(0, 0, 2, 1),
+            )
+            .unwrap();
+        let data = general_purpose::STANDARD.encode(&png);
+        assert!(iterm2.current.data.ends_with(&format!(
+            "]1337;File=inline=1;size={};width=2;height=1;preserveAspectRatio=1;\
+             doNotMoveCursor=1:{data}\x07",
+            png.len()
+        )));
+    }
+
+    #[test]
+    fn test_terminal_scaling_jpeg() {
+        // Transparent red, on a transparent white background.
+        let img = DynamicImage::from(ImageBuffer::from_pixel(16, 16, Rgba([255u8, 0, 0, 0])));
+        let source = ImageSource::new(img, (8, 8), Rgba([255, 255, 255, 0]));
+        let options = Iterm2Options {
+            encoding: Iterm2Encoding::Jpeg { quality: 90 },
+            terminal_scaling: true,
+            ..Iterm2Options::default()
+        };
+        let mut iterm2 = StatefulIterm2::new(source, (8, 8), false, options);
+        iterm2
+            .resize_encode(
+                &Resize::Fit(None),
+                Rgba([255, 255, 255, 0]),
+                Rect::new(0, 0, 2, 2),
+            )
+            .unwrap();
+        let jpeg = general_purpose::STANDARD
+            .decode(&iterm2.payload.data)
+            .unwrap();
+        let decoded = image::load_from_memory(&jpeg).unwrap().to_rgb8();
+        let [r, g, b] = decoded.get_pixel(8, 8).0;
+        assert!(r > 240 && g > 240 && b > 240, "{:?}", [r, g, b]);
+    }
+
+    #[test]
+    #[cfg(feature = "image-defaults")]
+    fn test_terminal_scaling() {
+        let img = DynamicImage::from(ImageBuffer::from_pixel(40, 20, Rgba([1u8, 2, 3, 255])));
+        let source = ImageSource::new(img, (10, 10), Rgba([0, 0, 0, 0]));
+        let options = Iterm2Options {
+            terminal_scaling: true,
+            ..Iterm2Options::default()
+        };
+        let mut iterm2 = StatefulIterm2::new(source, (10, 10), false, options);
+        let background_color = Rgba([0, 0, 0, 0]);
+
+        iterm2
+            .resize_encode(&Resize::Fit(None), background_color, Rect::new(0, 0, 4, 2))
+            .unwrap();
+        let payload = iterm2.payload.data.clone();
+        assert!(iterm2
+            .current
+            .data
+            .contains(";width=4;height=2;preserveAspectRatio=1;"));
+
+        iterm2
+            .resize_encode(&Resize::Fit(None), background_color, Rect::new(0, 0, 2, 1))
+            .unwrap();
+        assert!(iterm2
+            .current
+            .data
+            .contains(";width=2;height=1;preserveAspectRatio=1;"));
+        assert!(iterm2.current.data.ends_with(&format!(":{payload}\x07")));
+    }
 }
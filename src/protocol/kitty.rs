@@ -1,33 +1,354 @@
 /// https://sw.kovidgoyal.net/kitty/graphics-protocol/#unicode-placeholders
-use std::fmt::Write;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::Write,
+    fs,
+    io::{self, Cursor},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
 
 use base64::{engine::general_purpose, Engine};
-use image::{DynamicImage, Rgba};
-use ratatui::{buffer::Buffer, layout::Rect};
+use flate2::{write::ZlibEncoder, Compression};
+use image::{DynamicImage, Rgba, RgbaImage};
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Color, Style},
+};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 use crate::{picker::cap_parser::Parser, FontSize, ImageSource, Resize, Result};
 
-use super::{ProtocolTrait, StatefulProtocolTrait};
+use super::{EncodeFailure, ImageFrame, ProtocolTrait, StatefulProtocolTrait};
 
-#[derive(Default, Clone, PartialEq)]
+/// How the image is placed into the cells.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum KittyPlacement {
+    /// A virtual placement (`U=1`) that is drawn with unicode placeholder characters. Works inside
+    /// tmux, and the terminal removes the image when the placeholders are overwritten.
+    ///
+    /// The cells only hold the placeholders, the image is transmitted when
+    /// [take_pending_sequences] is written to the terminal after the frame.
+    #[default]
+    Virtual,
+    /// A classic placement (`a=p`) at the cursor position, for terminals that implement the
+    /// graphics protocol but not unicode placeholders, like Konsole or WezTerm. The
+    /// [crate::picker::Picker] chooses it when the terminal identifies itself as one of those.
+    /// Does not work inside tmux.
+    ///
+    /// The terminal keeps the placement when its cells are overwritten, so it is only removed
+    /// when [take_pending_sequences] is written to the terminal after a frame where the widget
+    /// was not rendered.
+    Direct,
+}
+
+/// How the image data is sent to the terminal.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum TransmissionMedium {
+    /// Base64 encoded in the escape sequences (`t=d`). Works everywhere, including over ssh and
+    /// inside tmux, but is slow for large images.
+    #[default]
+    Direct,
+    /// A temporary file that the terminal reads and deletes (`t=t`). Only works if the terminal
+    /// runs on the same machine.
+    TempFile,
+    /// A POSIX shared memory object that the terminal reads and unlinks (`t=s`). Only works if the
+    /// terminal runs on the same machine, and not on Windows.
+    SharedMemory,
+}
+
+impl TransmissionMedium {
+    /// The value of the `t` key.
+    fn key(self) -> char {
+        match self {
+            TransmissionMedium::Direct => 'd',
+            TransmissionMedium::TempFile => 't',
+            TransmissionMedium::SharedMemory => 's',
+        }
+    }
+}
+
+/// How the pixel data is compressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum KittyCompression {
+    /// Uncompressed for small images or local transmission media, zlib for medium sized images,
+    /// and PNG for large images.
+    #[default]
+    Auto,
+    /// Raw RGBA pixels (`f=32`).
+    None,
+    /// Zlib deflated RGBA pixels (`f=32,o=z`).
+    Zlib,
+    /// PNG data (`f=100`), smallest but slowest to encode. Falls back to zlib without PNG
+    /// support in `image`.
+    Png,
+}
+
+/// Images with less raw RGBA bytes are sent uncompressed with [KittyCompression::Auto].
+const AUTO_ZLIB_MIN_BYTES: usize = 16 * 1024;
+/// Images with more raw RGBA bytes are sent as PNG with [KittyCompression::Auto].
+const AUTO_PNG_MIN_BYTES: usize = 1024 * 1024;
+
+/// Options for the Kitty protocol.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct KittyOptions {
+    pub placement: KittyPlacement,
+    /// Falls back to [TransmissionMedium::Direct] if the data cannot be written to the medium.
+    pub medium: TransmissionMedium,
+    pub compression: KittyCompression,
+    /// Transmit the source image once, and let the terminal scale and crop it into the area.
+    ///
+    /// This makes resizing almost free, but the image is scaled by the terminal instead of with
+    /// the [crate::FilterType] of the [Resize]. Only for [StatefulKitty].
+    pub server_side_scaling: bool,
+}
+
+#[derive(Default, Clone)]
 enum KittyProtoState {
     #[default]
     Place,
-    TransmitAndPlace(String),
+    TransmitAndPlace(Transmission),
 }
 
 impl KittyProtoState {
     // Produce the transmit sequence or None if it has already been produced before.
     fn make_transmit(&mut self) -> Option<String> {
         match self {
-            KittyProtoState::TransmitAndPlace(seq) => {
-                let seq = std::mem::take(seq);
+            KittyProtoState::TransmitAndPlace(transmission) => {
+                let Transmission { seq, files } = std::mem::take(transmission);
                 *self = KittyProtoState::Place;
+                // From here on, the terminal deletes the files after reading them.
+                files.iter().for_each(|file| file.release());
                 Some(seq)
             }
             KittyProtoState::Place => None,
         }
     }
+
+    // Append a sequence to the one that is produced next.
+    fn push(&mut self, data: &str) {
+        match self {
+            KittyProtoState::TransmitAndPlace(transmission) => transmission.seq.push_str(data),
+            KittyProtoState::Place => {
+                *self = KittyProtoState::TransmitAndPlace(Transmission {
+                    seq: data.to_string(),
+                    files: Vec::new(),
+                })
+            }
+        }
+    }
+}
+
+/// A transmit sequence, and the [MediumFile]s it refers to.
+///
+/// The files are deleted if the sequence is dropped without being rendered, for example when a
+/// resize replaces it.
+#[derive(Default, Clone)]
+struct Transmission {
+    seq: String,
+    files: Vec<Arc<MediumFile>>,
+}
+
+impl Transmission {
+    fn append(&mut self, other: Transmission) {
+        self.seq.push_str(&other.seq);
+        self.files.extend(other.files);
+    }
+}
+
+/// The image ids that have been transmitted, and the sequences that are still pending.
+///
+/// Kitty keeps image data until it is deleted explicitly, so every transmitted id is held by an
+/// [ImageId], which queues the deletion when the last protocol using it is dropped. The deletions
+/// are flushed by whichever [KittyPlacement::Direct] protocol renders next, or by
+/// [take_pending_sequences].
+struct Registry {
+    live: BTreeSet<u32>,
+    /// The ids, and whether the deletion must be wrapped for tmux.
+    pending_deletions: Vec<(u32, bool)>,
+    /// The transmissions of [KittyPlacement::Virtual] protocols that have been rendered.
+    pending_transmissions: String,
+    /// The [KittyPlacement::Direct] placements on screen, by image id and placement id.
+    direct_placements: BTreeMap<(u32, u32), DirectPlacement>,
+}
+
+struct DirectPlacement {
+    /// Rendered since the last [take_pending_sequences].
+    rendered: bool,
+    is_tmux: bool,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    live: BTreeSet::new(),
+    pending_deletions: Vec::new(),
+    pending_transmissions: String::new(),
+    direct_placements: BTreeMap::new(),
+});
+
+fn registry() -> MutexGuard<'static, Registry> {
+    // The registry stays consistent even if some thread panicked while holding the lock.
+    REGISTRY
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A random image id that is neither in use nor pending deletion.
+pub(crate) fn new_image_id() -> u32 {
+    let registry = registry();
+    loop {
+        let id = rand::random();
+        let pending = registry
+            .pending_deletions
+            .iter()
+            .any(|(pending, _)| *pending == id);
+        if id != 0 && !registry.live.contains(&id) && !pending {
+            return id;
+        }
+    }
+}
+
+/// The placement id of new protocols. Others are counted up from here by [new_placement_id].
+const FIRST_PLACEMENT_ID: u32 = 1;
+
+/// A placement id that is not used by any other protocol of this process.
+///
+/// Placement ids are encoded as the 24 bit underline color of the placeholders, so they wrap
+/// around at `0xFF_FFFF`, but never to [FIRST_PLACEMENT_ID].
+fn new_placement_id() -> u32 {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    let next = NEXT.fetch_add(1, Ordering::Relaxed);
+    FIRST_PLACEMENT_ID + 1 + next % (0xFF_FFFF - FIRST_PLACEMENT_ID)
+}
+
+/// A transmitted image id, whose deletion is queued when the last clone is dropped.
+struct ImageId {
+    id: u32,
+    is_tmux: bool,
+    /// The hash of the source that has been transmitted under this id, shared by all placements
+    /// with [KittyOptions::server_side_scaling].
+    transmitted: Mutex<Option<u64>>,
+}
+
+impl ImageId {
+    fn register(id: u32, is_tmux: bool) -> Arc<ImageId> {
+        registry().live.insert(id);
+        Arc::new(ImageId {
+            id,
+            is_tmux,
+            transmitted: Mutex::new(None),
+        })
+    }
+
+    fn transmitted(&self) -> MutexGuard<'_, Option<u64>> {
+        self.transmitted
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Drop for ImageId {
+    fn drop(&mut self) {
+        let mut registry = registry();
+        registry.live.remove(&self.id);
+        registry.pending_deletions.push((self.id, self.is_tmux));
+        // Deleting the image also deletes its placements.
+        registry
+            .direct_placements
+            .retain(|(id, _), _| *id != self.id);
+    }
+}
+
+/// The sequence that deletes an image with its data and all its placements.
+fn delete_image(id: u32, is_tmux: bool) -> String {
+    let (start, escape, end) = Parser::escape_tmux(is_tmux);
+    format!("{start}{escape}_Gq=2,a=d,d=I,i={id}{escape}\\{end}")
+}
+
+/// The sequence that deletes one placement, but keeps the image data.
+fn delete_placement(id: u32, placement_id: u32, is_tmux: bool) -> String {
+    let (start, escape, end) = Parser::escape_tmux(is_tmux);
+    format!("{start}{escape}_Gq=2,a=d,d=i,i={id},p={placement_id}{escape}\\{end}")
+}
+
+/// The sequence that deletes the images whose protocols have been dropped.
+fn take_image_deletions(registry: &mut Registry) -> String {
+    std::mem::take(&mut registry.pending_deletions)
+        .iter()
+        .map(|(id, is_tmux)| delete_image(*id, *is_tmux))
+        .collect()
+}
+
+/// Take the Kitty sequences that are not part of the buffer: the transmissions of the
+/// [KittyPlacement::Virtual] images that have been rendered, and the deletions of the images whose
+/// protocols have been dropped and of the [KittyPlacement::Direct] placements that have not been
+/// rendered since the last call.
+///
+/// A transmission can't go into a cell, because ratatui takes the printable characters of the
+/// sequence for the width of the cell, and would redraw the cells after it. Direct placements stay
+/// on screen until they are deleted. Call this once after every drawn frame, and write the
+/// sequence to the terminal.
+///
+/// # Example
+/// ```rust
+/// use std::io::Write;
+/// use ratatui_image::protocol::kitty::take_pending_sequences;
+/// # fn main() -> std::io::Result<()> {
+/// // After `terminal.draw(...)`:
+/// if let Some(seq) = take_pending_sequences() {
+///     let mut stdout = std::io::stdout();
+///     stdout.write_all(seq.as_bytes())?;
+///     stdout.flush()?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn take_pending_sequences() -> Option<String> {
+    let mut registry = registry();
+    // Transmissions first, in case an image was dropped after it was rendered.
+    let mut seq = std::mem::take(&mut registry.pending_transmissions);
+    let deletions = take_image_deletions(&mut registry);
+    seq.push_str(&deletions);
+    registry
+        .direct_placements
+        .retain(|(id, placement_id), placement| {
+            if std::mem::take(&mut placement.rendered) {
+                return true;
+            }
+            seq.push_str(&delete_placement(*id, *placement_id, placement.is_tmux));
+            false
+        });
+    (!seq.is_empty()).then_some(seq)
+}
+
+/// Prepend the queued image deletions to the transmit sequence.
+fn with_pending_deletions(seq: Option<String>) -> Option<String> {
+    let mut deletions = take_image_deletions(&mut registry());
+    if deletions.is_empty() {
+        return seq;
+    }
+    deletions.push_str(&seq.unwrap_or_default());
+    Some(deletions)
 }
 
 // Fixed Kitty protocol (transmits image data on every render!)
@@ -35,27 +356,108 @@ impl KittyProtoState {
 pub struct Kitty {
     proto_state: KittyProtoState,
     unique_id: u32,
+    // Only held to delete the image when the last clone is dropped.
+    #[allow(dead_code)]
+    registered: Option<Arc<ImageId>>,
+    placement_id: u32,
     area: Rect,
+    src: (u32, u32, u32, u32),
+    z_index: i32,
+    is_tmux: bool,
+    options: KittyOptions,
 }
 
 impl Kitty {
     /// Create a FixedKitty from an image.
-    pub fn new(image: DynamicImage, area: Rect, id: u32, is_tmux: bool) -> Result<Self> {
-        let proto_state = KittyProtoState::TransmitAndPlace(transmit_virtual(&image, id, is_tmux));
-        Ok(Self {
-            proto_state,
+    pub fn new(
+        image: DynamicImage,
+        area: Rect,
+        id: u32,
+        is_tmux: bool,
+        options: KittyOptions,
+    ) -> Result<Self> {
+        let mut kitty = Self {
+            proto_state: KittyProtoState::Place,
             unique_id: id,
+            registered: Some(ImageId::register(id, is_tmux)),
+            placement_id: FIRST_PLACEMENT_ID,
             area,
-        })
+            src: (0, 0, image.width(), image.height()),
+            z_index: 0,
+            is_tmux,
+            options,
+        };
+        kitty.proto_state = KittyProtoState::TransmitAndPlace(transmit(
+            &image,
+            is_tmux,
+            &options,
+            &kitty.placement(),
+        ));
+        Ok(kitty)
+    }
+
+    /// Another placement of the same transmitted image, to show it several times.
+    ///
+    /// The image must have been transmitted, by rendering this protocol, before the new
+    /// placement is rendered.
+    ///
+    /// The placement id is the underline color of the cells. The termion backend does not write
+    /// underline colors, so there the terminal shows any one placement of the image in all of
+    /// them.
+    pub fn new_placement(&self) -> Kitty {
+        let mut kitty = Kitty {
+            proto_state: KittyProtoState::Place,
+            placement_id: new_placement_id(),
+            ..self.clone()
+        };
+        if self.options.placement == KittyPlacement::Virtual {
+            kitty.proto_state.push(&place_virtual(
+                &kitty.placement(),
+                kitty.area,
+                kitty.is_tmux,
+            ));
+        }
+        kitty
+    }
+
+    /// Set the z-index of the placement, see [crate::Image::z_index].
+    pub fn set_z_index(&mut self, z_index: i32) {
+        if z_index == self.z_index {
+            return;
+        }
+        self.z_index = z_index;
+        if self.options.placement == KittyPlacement::Virtual {
+            self.proto_state
+                .push(&place_virtual(&self.placement(), self.area, self.is_tmux));
+        }
+    }
+
+    fn placement(&self) -> Placement {
+        Placement {
+            id: self.unique_id,
+            placement_id: self.placement_id,
+            src: self.src,
+            z_index: self.z_index,
+        }
     }
 }
 
 impl ProtocolTrait for Kitty {
     fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        let placement = self.placement();
         // Transmit only once. This is why self is mut.
-        let seq = self.proto_state.make_transmit();
+        let proto_state = &mut self.proto_state;
 
-        render(area, self.area, buf, self.unique_id, seq);
+        match self.options.placement {
+            KittyPlacement::Virtual => render(area, self.area, buf, &placement, || {
+                proto_state.make_transmit()
+            }),
+            KittyPlacement::Direct => {
+                render_direct(area, self.area, buf, &placement, self.is_tmux, || {
+                    with_pending_deletions(proto_state.make_transmit())
+                })
+            }
+        }
     }
 
     fn area(&self) -> Rect {
@@ -68,32 +470,112 @@ pub struct StatefulKitty {
     source: ImageSource,
     font_size: FontSize,
     pub unique_id: u32,
+    registered: Arc<ImageId>,
+    placement_id: u32,
     rect: Rect,
+    // The part of the transmitted image that is placed, as `(x, y, width, height)`.
+    src: (u32, u32, u32, u32),
+    z_index: i32,
     hash: u64,
+    last_error: Option<EncodeFailure>,
     proto_state: KittyProtoState,
     is_tmux: bool,
+    options: KittyOptions,
+    loop_count: Option<u32>,
 }
 
 impl StatefulKitty {
-    pub fn new(source: ImageSource, font_size: FontSize, id: u32, is_tmux: bool) -> StatefulKitty {
+    pub fn new(
+        source: ImageSource,
+        font_size: FontSize,
+        id: u32,
+        is_tmux: bool,
+        options: KittyOptions,
+    ) -> StatefulKitty {
         StatefulKitty {
             source,
             font_size,
             unique_id: id,
+            registered: ImageId::register(id, is_tmux),
+            placement_id: FIRST_PLACEMENT_ID,
             rect: Rect::default(),
+            src: (0, 0, 0, 0),
+            z_index: 0,
             hash: u64::default(),
+            last_error: None,
             proto_state: KittyProtoState::default(),
             is_tmux,
+            options,
+            loop_count: None,
+        }
+    }
+
+    /// Set how many times an animated image is played, `None` (the default) loops forever.
+    ///
+    /// Has no effect on still images. Takes effect on the next transmission.
+    pub fn set_loop_count(&mut self, loop_count: Option<u32>) {
+        self.loop_count = loop_count;
+    }
+
+    /// Set the z-index of the placement, see [crate::StatefulImage::z_index].
+    pub fn set_z_index(&mut self, z_index: i32) {
+        if z_index == self.z_index {
+            return;
+        }
+        self.z_index = z_index;
+        // Before the first encode, the z-index is part of the first placement.
+        let placed = !self.rect.is_empty();
+        if placed && self.options.placement == KittyPlacement::Virtual {
+            self.proto_state
+                .push(&place_virtual(&self.placement(), self.rect, self.is_tmux));
+        }
+    }
+
+    /// Another placement of the same image, to show it several times, possibly in other sizes.
+    ///
+    /// With [KittyOptions::server_side_scaling], the image is transmitted only once for all its
+    /// placements. Otherwise, every size needs its own transmission, so the new placement also
+    /// gets a new image id. Does not work with the termion backend, see [Kitty::new_placement].
+    pub fn new_placement(&self) -> StatefulKitty {
+        let mut kitty = StatefulKitty {
+            placement_id: new_placement_id(),
+            rect: Rect::default(),
+            proto_state: KittyProtoState::Place,
+            ..self.clone()
+        };
+        if !self.options.server_side_scaling {
+            kitty.unique_id = new_image_id();
+            kitty.registered = ImageId::register(kitty.unique_id, kitty.is_tmux);
+        }
+        kitty
+    }
+
+    fn placement(&self) -> Placement {
+        Placement {
+            id: self.unique_id,
+            placement_id: self.placement_id,
+            src: self.src,
+            z_index: self.z_index,
         }
     }
 }
 
 impl ProtocolTrait for StatefulKitty {
     fn render(&mut self, area: Rect, buf: &mut Buffer) {
+        let placement = self.placement();
         // Transmit only once. This is why self is mut.
-        let seq = self.proto_state.make_transmit();
+        let proto_state = &mut self.proto_state;
 
-        render(area, self.rect, buf, self.unique_id, seq);
+        match self.options.placement {
+            KittyPlacement::Virtual => render(area, self.rect, buf, &placement, || {
+                proto_state.make_transmit()
+            }),
+            KittyPlacement::Direct => {
+                render_direct(area, self.rect, buf, &placement, self.is_tmux, || {
+                    with_pending_deletions(proto_state.make_transmit())
+                })
+            }
+        }
     }
 
     fn area(&self) -> Rect {
@@ -114,77 +596,543 @@ impl StatefulProtocolTrait for StatefulKitty {
             self.source.hash != self.hash,
         )
     }
-    fn resize_encode(&mut self, resize: &Resize, background_color: Rgba<u8>, area: Rect) {
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
         if area.width == 0 || area.height == 0 {
-            return;
+            return Ok(());
         }
 
-        let img = resize.resize(&self.source, self.font_size, area, background_color);
-        let data = transmit_virtual(&img, self.unique_id, self.is_tmux);
+        if self.registered.id != self.unique_id {
+            // The public id has been changed.
+            self.registered = ImageId::register(self.unique_id, self.is_tmux);
+        }
+        let registered = Arc::clone(&self.registered);
+        let mut transmitted = registered.transmitted();
+        let mut data = Transmission::default();
+        if !self.options.server_side_scaling || *transmitted != Some(self.source.hash) {
+            if transmitted.is_some() {
+                // Delete the previous transmission right away, not queued, because the queue
+                // could be flushed after the new transmission under the same id.
+                data.seq
+                    .push_str(&delete_image(self.unique_id, self.is_tmux));
+            }
+            let (transmit_data, (width, height)) = self.transmit(resize, background_color, area);
+            data.append(transmit_data);
+            *transmitted = Some(self.source.hash);
+            self.src = (0, 0, width, height);
+        }
+        drop(transmitted);
+        if self.options.server_side_scaling {
+            self.src = resize.source_rect(&self.source.image, self.font_size, area);
+            if self.options.placement == KittyPlacement::Virtual {
+                data.seq
+                    .push_str(&place_virtual(&self.placement(), area, self.is_tmux));
+            }
+        }
         self.hash = self.source.hash;
         self.rect = area;
         // If resized then we must transmit again.
         self.proto_state = KittyProtoState::TransmitAndPlace(data);
+        Ok(())
+    }
+    fn last_error(&self) -> Option<&EncodeFailure> {
+        self.last_error.as_ref()
+    }
+    fn last_error_mut(&mut self) -> &mut Option<EncodeFailure> {
+        &mut self.last_error
+    }
+}
+
+impl StatefulKitty {
+    /// Transmit the image resized to the area, or the source image with server side scaling.
+    ///
+    /// Returns the sequence, and the size of the transmitted image.
+    fn transmit(
+        &self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> (Transmission, (u32, u32)) {
+        let options = &self.options;
+        if self.source.is_animated() {
+            let resized: Vec<ImageFrame>;
+            let frames = if options.server_side_scaling {
+                &self.source.frames[..]
+            } else {
+                resized = self
+                    .source
+                    .frames
+                    .iter()
+                    .map(|frame| ImageFrame {
+                        image: resize.resize_frame(
+                            &frame.image,
+                            self.font_size,
+                            area,
+                            background_color,
+                        ),
+                        delay: frame.delay,
+                    })
+                    .collect();
+                &resized[..]
+            };
+            let size = (frames[0].image.width(), frames[0].image.height());
+            let data = transmit_animated(
+                frames,
+                self.is_tmux,
+                options,
+                &self.placement(),
+                self.loop_count,
+            );
+            (data, size)
+        } else if options.server_side_scaling {
+            let img = &self.source.image;
+            let data = transmit(img, self.is_tmux, options, &self.placement());
+            (data, (img.width(), img.height()))
+        } else {
+            let img = resize.resize(&self.source, self.font_size, area, background_color);
+            let data = transmit(&img, self.is_tmux, options, &self.placement());
+            (data, (img.width(), img.height()))
+        }
+    }
+}
+
+/// Draw the unicode placeholders of a virtual placement, each cell with its own row and column.
+///
+/// The lower 24 bits of the image id are the foreground color of the cells, the highest byte is
+/// the third diacritic. The placement id is the underline color. This way the cells behave like
+/// ordinary text: they can be redrawn partially, overdrawn by other widgets, and diffed by
+/// ratatui.
+///
+/// The transmit sequence is queued for [take_pending_sequences], but only taken from `seq` if
+/// some cell is rendered.
+fn render(
+    area: Rect,
+    rect: Rect,
+    buf: &mut Buffer,
+    placement: &Placement,
+    seq: impl FnOnce() -> Option<String>,
+) {
+    let [id_extra, id_r, id_g, id_b] = placement.id.to_be_bytes();
+    let [_, placement_r, placement_g, placement_b] = placement.placement_id.to_be_bytes();
+    let style = Style::new()
+        .fg(Color::Rgb(id_r, id_g, id_b))
+        .underline_color(Color::Rgb(placement_r, placement_g, placement_b));
+
+    let width = area.width.min(rect.width);
+    let height = area.height.min(rect.height);
+    for y in 0..height {
+        for x in 0..width {
+            let mut symbol = String::new();
+            add_placeholder(&mut symbol, x, y, id_extra);
+            if let Some(cell) = buf.cell_mut((area.left() + x, area.top() + y)) {
+                cell.set_symbol(&symbol).set_style(style);
+            }
+        }
+    }
+
+    if width == 0 || height == 0 || buf.cell((area.left(), area.top())).is_none() {
+        return;
+    }
+    if let Some(seq) = seq() {
+        registry().pending_transmissions.push_str(&seq);
     }
 }
 
-fn render(area: Rect, rect: Rect, buf: &mut Buffer, id: u32, mut seq: Option<String>) {
-    let [id_extra, id_r, id_g, id_b] = id.to_be_bytes();
-    // Set the background color to the kitty id
-    let id_color = format!("\x1b[38;2;{id_r};{id_g};{id_b}m");
+/// How a transmitted image is placed.
+struct Placement {
+    id: u32,
+    /// At most 24 bits, see [new_placement_id].
+    placement_id: u32,
+    /// The part of the image that is placed, as `(x, y, width, height)` in pixels.
+    src: (u32, u32, u32, u32),
+    z_index: i32,
+}
 
-    // Draw each line of unicode placeholders but all into the first cell.
-    // I couldn't work out actually drawing into each cell of the buffer so
-    // that `.set_skip(true)` would be made unnecessary. Maybe some other escape
-    // sequence gets sneaked in somehow.
-    // It could also be made so that each cell starts and ends its own escape sequence
-    // with the image id, but maybe that's worse.
-    for y in 0..(area.height.min(rect.height)) {
-        // If not transmitted in previous renders, only transmit once at the
-        // first line for obvious reasons.
-        let mut symbol = seq.take().unwrap_or_default();
+/// Create a virtual placement of the `src` part of the image, scaled into the `rect` cells.
+///
+/// This replaces the previous placement with the same placement id.
+fn place_virtual(placement: &Placement, rect: Rect, is_tmux: bool) -> String {
+    let (start, escape, end) = Parser::escape_tmux(is_tmux);
+    let Placement {
+        id,
+        placement_id: p,
+        src: (x, y, w, h),
+        z_index,
+    } = placement;
+    let (c, r) = (rect.width, rect.height);
+    let z = z_key(*z_index);
+    format!("{start}{escape}_Gq=2,a=p,U=1,i={id},p={p},x={x},y={y},w={w},h={h},c={c},r={r}{z}{escape}\\{end}")
+}
 
-        // Save cursor postion, including fg color which is what we want.
-        symbol.push_str("\x1b[s");
+/// The z-index key of a placement, omitted for the default of `0`.
+fn z_key(z_index: i32) -> String {
+    if z_index == 0 {
+        String::new()
+    } else {
+        format!(",z={z_index}")
+    }
+}
+
+/// Place the image at the first cell of the area, with the classic `a=p` placement.
+///
+/// The previous placement is deleted first, which moves the image when the area has moved. The
+/// `src` part of the image is scaled into the cells, and only the visible part of it is placed if
+/// the area is smaller than `rect`. The placement is registered as rendered in this frame, see
+/// [take_pending_sequences].
+fn render_direct(
+    area: Rect,
+    rect: Rect,
+    buf: &mut Buffer,
+    placement: &Placement,
+    is_tmux: bool,
+    seq: impl FnOnce() -> Option<String>,
+) {
+    let render_area = Rect::new(
+        area.x,
+        area.y,
+        area.width.min(rect.width),
+        area.height.min(rect.height),
+    );
+    if render_area.is_empty() || buf.cell((render_area.x, render_area.y)).is_none() {
+        return;
+    }
 
-        // Start unicode placeholder sequence
-        symbol.push_str(&id_color);
-        add_placeholder(&mut symbol, 0, y, id_extra);
+    let (start, escape, end) = Parser::escape_tmux(is_tmux);
+    let mut symbol = seq().unwrap_or_default();
+    let Placement {
+        id,
+        placement_id: p,
+        src,
+        z_index,
+    } = placement;
+    let (x, y) = (src.0, src.1);
+    let w = src.2 * render_area.width as u32 / rect.width as u32;
+    let h = src.3 * render_area.height as u32 / rect.height as u32;
+    let (c, r) = (render_area.width, render_area.height);
+    let z = z_key(*z_index);
+    write!(
+        symbol,
+        "{start}{escape}_Gq=2,a=d,d=i,i={id},p={p}{escape}\\\
+         {escape}_Gq=2,a=p,i={id},p={p},x={x},y={y},w={w},h={h},c={c},r={r}{z},C=1{escape}\\{end}"
+    )
+    .unwrap();
+    // `C=1` keeps the cursor in place. The other cells are skipped, so ratatui moves the cursor
+    // explicitly before it draws anything after this cell.
 
-        for x in 1..(area.width.min(rect.width)) {
-            // Add entire row with positions
-            // Use inherited diacritic values
-            symbol.push('\u{10EEEE}');
-            // Skip or something may overwrite it
-            buf.cell_mut((area.left() + x, area.top() + y))
-                .map(|cell| cell.set_skip(true));
+    buf.cell_mut((render_area.x, render_area.y))
+        .map(|cell| cell.set_symbol(&symbol));
+    for y in render_area.top()..render_area.bottom() {
+        for x in render_area.left()..render_area.right() {
+            if x == render_area.x && y == render_area.y {
+                continue;
+            }
+            buf.cell_mut((x, y)).map(|cell| cell.set_skip(true));
+        }
+    }
+    registry().direct_placements.insert(
+        (*id, *p),
+        DirectPlacement {
+            rendered: true,
+            is_tmux,
+        },
+    );
+}
+
+/// The transmit action: transmit and virtual-place, or only transmit if the image is placed
+/// separately.
+fn transmit_action(options: &KittyOptions, placement: &Placement) -> String {
+    match options.placement {
+        KittyPlacement::Virtual if !options.server_side_scaling => {
+            let p = placement.placement_id;
+            format!("a=T,U=1,p={p}{}", z_key(placement.z_index))
         }
+        _ => "a=t".to_string(),
+    }
+}
 
-        // Restore saved cursor position including color, and now we have to move back to
-        // the end of the area.
-        let right = area.width - 1;
-        let down = area.height - 1;
-        symbol.push_str(&format!("\x1b[u\x1b[{right}C\x1b[{down}B"));
+/// The pixel data of an image, compressed as the options say.
+struct Pixels {
+    /// The format and compression keys.
+    format: &'static str,
+    /// The width and height, only needed for raw pixels.
+    size: Option<(u32, u32)>,
+    bytes: Vec<u8>,
+}
+
+impl Pixels {
+    fn new(img: &RgbaImage, options: &KittyOptions) -> Pixels {
+        let size = Some((img.width(), img.height()));
+        let raw = img.as_raw();
+        let compression = match options.compression {
+            KittyCompression::Auto if options.medium != TransmissionMedium::Direct => {
+                // Compressing only costs time if the data doesn't go over the tty.
+                KittyCompression::None
+            }
+            KittyCompression::Auto if raw.len() < AUTO_ZLIB_MIN_BYTES => KittyCompression::None,
+            KittyCompression::Auto if raw.len() < AUTO_PNG_MIN_BYTES => KittyCompression::Zlib,
+            KittyCompression::Auto => KittyCompression::Png,
+            compression => compression,
+        };
+        let zlib = || {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            io::Write::write_all(&mut encoder, raw)
+                .and_then(|_| encoder.finish())
+                .ok()
+                .map(|bytes| Pixels {
+                    format: "f=32,o=z",
+                    size,
+                    bytes,
+                })
+        };
+        let compressed = match compression {
+            KittyCompression::Zlib => zlib(),
+            KittyCompression::Png => {
+                let mut png = Vec::new();
+                img.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+                    .ok()
+                    .map(|_| Pixels {
+                        format: "f=100",
+                        size: None,
+                        bytes: png,
+                    })
+                    // Without PNG support in `image`.
+                    .or_else(zlib)
+            }
+            KittyCompression::Auto | KittyCompression::None => None,
+        };
+        // Fall back to raw pixels if compression failed.
+        compressed.unwrap_or_else(|| Pixels {
+            format: "f=32",
+            size,
+            bytes: raw.clone(),
+        })
+    }
 
-        buf.cell_mut((area.left(), area.top() + y))
-            .map(|cell| cell.set_symbol(&symbol));
+    /// The format, medium and size keys.
+    fn control(&self, t: char) -> String {
+        match self.size {
+            Some((w, h)) => format!("{},t={t},s={w},v={h}", self.format),
+            None => format!("{},t={t}", self.format),
+        }
     }
 }
 
 /// Create a kitty escape sequence for transmitting and virtual-placement.
 ///
-/// The image will be transmitted as RGB8 in chunks of 4096 bytes.
+/// The image will be transmitted as RGBA8, compressed depending on the options, in chunks of 4096
+/// bytes.
 /// A "virtual placement" (U=1) is created so that we can place it using unicode placeholders.
 /// Removing the placements when the unicode placeholder is no longer there is being handled
 /// automatically by kitty.
-fn transmit_virtual(img: &DynamicImage, id: u32, is_tmux: bool) -> String {
-    let (w, h) = (img.width(), img.height());
-    let img_rgba8 = img.to_rgba8();
-    let bytes = img_rgba8.as_raw();
+/// With [KittyPlacement::Direct], the image is only transmitted, and placed when rendering.
+fn transmit(
+    img: &DynamicImage,
+    is_tmux: bool,
+    options: &KittyOptions,
+    placement: &Placement,
+) -> Transmission {
+    let id = placement.id;
+    let pixels = Pixels::new(&img.to_rgba8(), options);
 
     let (start, escape, end) = Parser::escape_tmux(is_tmux);
-    let mut data = String::from(start);
+    let mut data = Transmission {
+        seq: String::from(start),
+        files: Vec::new(),
+    };
+
+    // Transmit, and virtual-place unless placing directly
+    let action = transmit_action(options, placement);
+    let control = |t| format!("i={id},{action},{}", pixels.control(t));
+    write_payload(&mut data, escape, control, &pixels.bytes, options.medium);
+    data.seq.push_str(end);
+
+    data
+}
+
+/// Create a kitty escape sequence for transmitting all frames of an animation, virtual-placing
+/// it, and starting the animation.
+///
+/// The first frame is transmitted like [transmit], the following frames are added with
+/// `a=f`, each with its own gap. Kitty then plays the animation on its own, we never need to
+/// re-render for a frame change.
+/// See https://sw.kovidgoyal.net/kitty/graphics-protocol/#animation
+fn transmit_animated(
+    frames: &[ImageFrame],
+    is_tmux: bool,
+    options: &KittyOptions,
+    placement: &Placement,
+    loop_count: Option<u32>,
+) -> Transmission {
+    let id = placement.id;
+    let (start, escape, end) = Parser::escape_tmux(is_tmux);
+    let mut data = Transmission {
+        seq: String::from(start),
+        files: Vec::new(),
+    };
+
+    for (i, frame) in frames.iter().enumerate() {
+        let pixels = Pixels::new(&frame.image.to_rgba8(), options);
+        let gap = frame.delay.as_millis();
+        let control = |t| {
+            if i == 0 {
+                let action = transmit_action(options, placement);
+                format!("i={id},{action},{}", pixels.control(t))
+            } else {
+                format!("i={id},a=f,{},z={gap}", pixels.control(t))
+            }
+        };
+        write_payload(&mut data, escape, control, &pixels.bytes, options.medium);
+
+        if i == 0 {
+            // The gap of the root frame can only be set with an animation control command.
+            write!(data.seq, "{escape}_Gq=2,i={id},a=a,r=1,z={gap}{escape}\\").unwrap();
+        }
+    }
+
+    // Start the animation. `v=1` means loop forever, `v=n` means loop `n-1` times.
+    let loops = loop_count.map_or(1, |count| count.saturating_add(1).max(2));
+    write!(data.seq, "{escape}_Gq=2,i={id},a=a,s=3,v={loops}{escape}\\").unwrap();
+    data.seq.push_str(end);
+
+    data
+}
+
+/// Write the transmit command, with the path of a [MediumFile] as payload, or else the data
+/// itself if the medium is not available.
+///
+/// `control` makes the control keys from the `t` key of the medium that is used.
+fn write_payload(
+    data: &mut Transmission,
+    escape: &str,
+    control: impl FnOnce(char) -> String,
+    bytes: &[u8],
+    medium: TransmissionMedium,
+) {
+    match MediumFile::write(bytes, medium) {
+        Some(file) => {
+            let control = control(medium.key());
+            let payload = general_purpose::STANDARD.encode(file.path());
+            write!(data.seq, "{escape}_Gq=2,{control};{payload}{escape}\\").unwrap();
+            data.files.push(Arc::new(file));
+        }
+        None => {
+            let control = control(TransmissionMedium::Direct.key());
+            write_chunked(&mut data.seq, escape, &control, bytes);
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn write_shared_memory(name: &str, bytes: &[u8]) -> io::Result<()> {
+    use io::Write;
+    use rustix::{fs::Mode, shm};
+
+    let fd = shm::shm_open(
+        name,
+        shm::ShmOFlags::CREATE | shm::ShmOFlags::EXCL | shm::ShmOFlags::RDWR,
+        Mode::RUSR | Mode::WUSR,
+    )?;
+    let result = fs::File::from(fd).write_all(bytes);
+    if result.is_err() {
+        let _ = shm::shm_unlink(name);
+    }
+    result
+}
+
+#[cfg(windows)]
+fn write_shared_memory(_name: &str, _bytes: &[u8]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// A temporary file or shared memory object with data for the terminal to read.
+///
+/// The terminal deletes it after reading. Until it has been [released](MediumFile::release) to
+/// the terminal, it is deleted on drop, so that nothing is left behind if the sequence that refers
+/// to it is never written.
+pub(crate) struct MediumFile {
+    medium: TransmissionMedium,
+    path: String,
+    released: AtomicBool,
+}
 
+impl MediumFile {
+    /// Write `bytes` to the medium, or `None` if it is [TransmissionMedium::Direct] or not
+    /// available.
+    fn write(bytes: &[u8], medium: TransmissionMedium) -> Option<MediumFile> {
+        // The terminal only reads files whose name contains this, in a temporary directory.
+        let name = format!(
+            "tty-graphics-protocol-{}-{}",
+            std::process::id(),
+            rand::random::<u32>()
+        );
+        let path = match medium {
+            TransmissionMedium::Direct => None,
+            TransmissionMedium::TempFile => {
+                let path = env::temp_dir().join(name);
+                match (fs::write(&path, bytes), path.to_str()) {
+                    (Ok(()), Some(path_str)) => Some(path_str.to_string()),
+                    (Ok(()), None) => {
+                        let _ = fs::remove_file(&path);
+                        None
+                    }
+                    (Err(_), _) => None,
+                }
+            }
+            TransmissionMedium::SharedMemory => {
+                let name = format!("/{name}");
+                write_shared_memory(&name, bytes).ok().map(|_| name)
+            }
+        }?;
+        Some(MediumFile {
+            medium,
+            path,
+            released: AtomicBool::new(false),
+        })
+    }
+
+    /// A single pixel, to query the terminal for support with
+    /// [crate::picker::cap_parser::Parser::query_kitty_media].
+    pub(crate) fn probe(medium: TransmissionMedium) -> Option<MediumFile> {
+        // One RGB pixel, as the query is `f=24,s=1,v=1`.
+        MediumFile::write(&[0, 0, 0], medium)
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Leave the deletion to the terminal, once the sequence has been written.
+    fn release(&self) {
+        self.released.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for MediumFile {
+    fn drop(&mut self) {
+        if self.released.load(Ordering::Relaxed) {
+            return;
+        }
+        match self.medium {
+            TransmissionMedium::Direct => {}
+            TransmissionMedium::TempFile => {
+                let _ = fs::remove_file(&self.path);
+            }
+            #[cfg(not(windows))]
+            TransmissionMedium::SharedMemory => {
+                let _ = rustix::shm::shm_unlink(self.path.as_str());
+            }
+            #[cfg(windows)]
+            TransmissionMedium::SharedMemory => {}
+        }
+    }
+}
+
+/// Write `bytes` as base64 payload in chunks, where the first chunk carries the `control` keys.
+fn write_chunked(data: &mut String, escape: &str, control: &str, bytes: &[u8]) {
     // Max chunk size is 4096 bytes of base64 encoded data
     let chunks = bytes.chunks(4096 / 4 * 3);
     let chunk_count = chunks.len();
@@ -197,13 +1145,9 @@ fn transmit_virtual(img: &DynamicImage, id: u32, is_tmux: bool) -> String {
 
         match i {
             0 => {
-                // Transmit and virtual-place but keep sending chunks
+                // Keep sending chunks
                 let more = if chunk_count > 1 { 1 } else { 0 };
-                write!(
-                    data,
-                    "_Gq=2,i={id},a=T,U=1,f=32,t=d,s={w},v={h},m={more};{payload}"
-                )
-                .unwrap();
+                write!(data, "_Gq=2,{control},m={more};{payload}").unwrap();
             }
             n if n + 1 == chunk_count => {
                 // m=0 means over
@@ -217,9 +1161,6 @@ fn transmit_virtual(img: &DynamicImage, id: u32, is_tmux: bool) -> String {
         data.push_str(escape);
         write!(data, "\\").unwrap();
     }
-    data.push_str(end);
-
-    data
 }
 
 fn add_placeholder(str: &mut String, x: u16, y: u16, id_extra: u8) {
@@ -538,3 +1479,336 @@ fn diacritic(y: u16) -> char {
         DIACRITICS[y as usize]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Mutex, MutexGuard},
+        time::Duration,
+    };
+
+    use std::io::Read;
+
+    use base64::{engine::general_purpose, Engine};
+    use flate2::read::ZlibDecoder;
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::{buffer::Buffer, layout::Rect, style::Color};
+
+    use super::{
+        new_image_id, registry, render, render_direct, take_pending_sequences, transmit,
+        transmit_animated, ImageFrame, Kitty, KittyCompression, KittyOptions, KittyProtoState,
+        Pixels, Placement, StatefulKitty, Transmission, TransmissionMedium,
+    };
+    use crate::{protocol::StatefulProtocolTrait, ImageSource, Resize};
+
+    fn placement(id: u32) -> Placement {
+        Placement {
+            id,
+            placement_id: 1,
+            src: (0, 0, 0, 0),
+            z_index: 0,
+        }
+    }
+
+    /// Held by the tests that take the pending sequences of the global registry, so that they
+    /// don't take each other's.
+    fn lock_pending() -> MutexGuard<'static, ()> {
+        static PENDING: Mutex<()> = Mutex::new(());
+        PENDING
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    #[test]
+    fn test_transmit_animated() {
+        let frame = |delay| ImageFrame {
+            image: DynamicImage::from(ImageBuffer::from_pixel(2, 2, Rgba([255u8, 0, 0, 255]))),
+            delay: Duration::from_millis(delay),
+        };
+        let data = transmit_animated(
+            &[frame(100), frame(50)],
+            false,
+            &KittyOptions::default(),
+            &placement(42),
+            Some(3),
+        );
+        let controls: Vec<&str> = data
+            .seq
+            .split("\x1b_G")
+            .filter_map(|seq| seq.split([';', '\x1b']).next())
+            .filter(|control| !control.is_empty())
+            .collect();
+        assert_eq!(
+            controls,
+            vec![
+                "q=2,i=42,a=T,U=1,p=1,f=32,t=d,s=2,v=2,m=0",
+                "q=2,i=42,a=a,r=1,z=100",
+                "q=2,i=42,a=f,f=32,t=d,s=2,v=2,z=50,m=0",
+                "q=2,i=42,a=a,s=3,v=4",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_placeholders() {
+        let _pending = lock_pending();
+        let mut buf = Buffer::empty(Rect::new(0, 0, 10, 5));
+        render(
+            Rect::new(2, 1, 3, 2),
+            Rect::new(0, 0, 4, 2),
+            &mut buf,
+            &Placement {
+                placement_id: 0x0A_0B_0C,
+                ..placement(0x01_02_03_04)
+            },
+            || Some("seq".to_string()),
+        );
+        assert_eq!(buf[(2, 1)].symbol(), "\u{10EEEE}\u{305}\u{305}\u{30D}");
+        assert_eq!(buf[(3, 1)].symbol(), "\u{10EEEE}\u{305}\u{30D}\u{30D}");
+        assert_eq!(buf[(3, 2)].symbol(), "\u{10EEEE}\u{30D}\u{30D}\u{30D}");
+        assert_eq!(buf[(4, 2)].symbol(), "\u{10EEEE}\u{30D}\u{30E}\u{30D}");
+        assert_eq!(buf[(3, 2)].fg, Color::Rgb(2, 3, 4));
+        assert_eq!(buf[(3, 2)].underline_color, Color::Rgb(10, 11, 12));
+        assert!(!buf[(3, 1)].skip);
+        assert_eq!(buf[(5, 1)].symbol(), " ");
+        assert!(take_pending_sequences().unwrap().starts_with("seq"));
+
+        // Each cell is drawn once, and not again in the following frame.
+        let mut next = Buffer::empty(buf.area);
+        render(
+            Rect::new(2, 1, 3, 2),
+            Rect::new(0, 0, 4, 2),
+            &mut next,
+            &Placement {
+                placement_id: 0x0A_0B_0C,
+                ..placement(0x01_02_03_04)
+            },
+            || Some("seq".to_string()),
+        );
+        let updated = |diff: Vec<(u16, u16, &ratatui::buffer::Cell)>| {
+            diff.iter().map(|(x, y, _)| (*x, *y)).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            updated(Buffer::empty(buf.area).diff(&buf)),
+            vec![(2, 1), (3, 1), (4, 1), (2, 2), (3, 2), (4, 2)]
+        );
+        assert!(updated(buf.diff(&next)).is_empty());
+        take_pending_sequences();
+
+        // The sequence is kept for later if the area is outside of the buffer.
+        render(
+            Rect::new(20, 20, 3, 2),
+            Rect::new(0, 0, 4, 2),
+            &mut buf,
+            &placement(1),
+            || panic!("should not take the sequence"),
+        );
+    }
+
+    #[test]
+    fn test_render_direct() {
+        let _pending = lock_pending();
+        let id = new_image_id();
+        let mut buf = Buffer::empty(Rect::new(0, 0, 10, 5));
+        render_direct(
+            Rect::new(2, 1, 3, 2),
+            Rect::new(0, 0, 4, 2),
+            &mut buf,
+            &Placement {
+                placement_id: 3,
+                src: (0, 0, 40, 40),
+                z_index: -1,
+                ..placement(id)
+            },
+            false,
+            || None,
+        );
+        assert_eq!(
+            buf[(2, 1)].symbol(),
+            format!("\x1b_Gq=2,a=d,d=i,i={id},p=3\x1b\\\x1b_Gq=2,a=p,i={id},p=3,x=0,y=0,w=30,h=40,c=3,r=2,z=-1,C=1\x1b\\")
+        );
+        assert!(buf[(4, 2)].skip);
+        assert!(!buf[(5, 1)].skip);
+
+        // Deleted once a frame passes without rendering it.
+        let deletion = format!("\x1b_Gq=2,a=d,d=i,i={id},p=3\x1b\\");
+        assert!(!take_pending_sequences().is_some_and(|deletions| deletions.contains(&deletion)));
+        assert!(take_pending_sequences().unwrap().contains(&deletion));
+        assert!(!registry().direct_placements.contains_key(&(id, 3)));
+    }
+
+    #[test]
+    fn test_transmit_temp_file() {
+        let img = DynamicImage::from(ImageBuffer::from_pixel(1, 1, Rgba([1u8, 2, 3, 4])));
+        let options = KittyOptions {
+            medium: TransmissionMedium::TempFile,
+            ..KittyOptions::default()
+        };
+        let control_and_path = |seq: &str| {
+            let (control, payload) = seq
+                .trim_start_matches("\x1b_G")
+                .trim_end_matches("\x1b\\")
+                .split_once(';')
+                .unwrap();
+            let path = general_purpose::STANDARD.decode(payload).unwrap();
+            (control.to_string(), String::from_utf8(path).unwrap())
+        };
+
+        let data = transmit(&img, false, &options, &placement(1));
+        let (control, path) = control_and_path(&data.seq);
+        assert_eq!(control, "q=2,i=1,a=T,U=1,p=1,f=32,t=t,s=1,v=1");
+        assert!(path.contains("tty-graphics-protocol"));
+        assert_eq!(std::fs::read(&path).unwrap(), [1, 2, 3, 4]);
+        // Never rendered, e.g. replaced by a resize.
+        drop(data);
+        assert!(!std::path::Path::new(&path).exists());
+
+        // Once rendered, the terminal deletes the file.
+        let mut state =
+            KittyProtoState::TransmitAndPlace(transmit(&img, false, &options, &placement(1)));
+        let (_, path) = control_and_path(&state.make_transmit().unwrap());
+        drop(state);
+        assert!(std::path::Path::new(&path).exists());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_compression() {
+        let img = ImageBuffer::from_pixel(100, 100, Rgba([1u8, 2, 3, 4]));
+        let pixels = |compression| {
+            Pixels::new(
+                &img,
+                &KittyOptions {
+                    compression,
+                    ..KittyOptions::default()
+                },
+            )
+        };
+        let auto = pixels(KittyCompression::Auto);
+        assert_eq!(auto.control('d'), "f=32,o=z,t=d,s=100,v=100");
+        let mut decoder = ZlibDecoder::new(&auto.bytes[..]);
+        let mut raw = vec![];
+        decoder.read_to_end(&mut raw).unwrap();
+        assert_eq!(raw, *img.as_raw());
+
+        assert_eq!(pixels(KittyCompression::None).bytes.len(), 40000);
+        let png = pixels(KittyCompression::Png);
+        #[cfg(feature = "image-defaults")]
+        {
+            assert_eq!(png.control('d'), "f=100,t=d");
+            assert!(png.bytes.starts_with(b"\x89PNG"));
+        }
+        #[cfg(not(feature = "image-defaults"))]
+        assert_eq!(png.control('d'), "f=32,o=z,t=d,s=100,v=100");
+    }
+
+    #[test]
+    fn test_delete_on_drop() {
+        let _pending = lock_pending();
+        let id = new_image_id();
+        let kitty = Kitty::new(
+            DynamicImage::from(ImageBuffer::from_pixel(1, 1, Rgba([0u8, 0, 0, 255]))),
+            Rect::new(0, 0, 1, 1),
+            id,
+            false,
+            KittyOptions::default(),
+        )
+        .unwrap();
+        let clone = kitty.clone();
+        drop(kitty);
+        assert!(registry().live.contains(&id));
+        drop(clone);
+        assert!(!registry().live.contains(&id));
+
+        let seq = take_pending_sequences().unwrap();
+        assert!(seq.contains(&format!("\x1b_Gq=2,a=d,d=I,i={id}\x1b\\")));
+    }
+
+    #[test]
+    fn test_server_side_scaling() {
+        let image = DynamicImage::from(ImageBuffer::from_pixel(40, 40, Rgba([0u8, 0, 0, 255])));
+        let source = ImageSource::new(image, (10, 10), Rgba([0, 0, 0, 0]));
+        let options = KittyOptions {
+            server_side_scaling: true,
+            ..KittyOptions::default()
+        };
+        let mut kitty = StatefulKitty::new(source, (10, 10), new_image_id(), false, options);
+        let id = kitty.unique_id;
+        let resize = Resize::Crop(None);
+        let background_color = Rgba([0, 0, 0, 0]);
+        // Created before the image is transmitted.
+        let mut early = kitty.new_placement();
+
+        kitty
+            .resize_encode(&resize, background_color, Rect::new(0, 0, 2, 2))
+            .unwrap();
+        let KittyProtoState::TransmitAndPlace(Transmission { seq: data, .. }) = &kitty.proto_state
+        else {
+            panic!("should transmit");
+        };
+        assert!(data.contains("a=t,f=32,t=d,s=40,v=40"));
+        assert!(data.ends_with(&format!(
+            "\x1b_Gq=2,a=p,U=1,i={id},p=1,x=0,y=0,w=20,h=20,c=2,r=2\x1b\\"
+        )));
+
+        // Only the placement changes on resize.
+        kitty
+            .resize_encode(&resize, background_color, Rect::new(0, 0, 3, 1))
+            .unwrap();
+        let KittyProtoState::TransmitAndPlace(Transmission { seq: data, .. }) = &kitty.proto_state
+        else {
+            panic!("should place");
+        };
+        assert_eq!(
+            data,
+            &format!("\x1b_Gq=2,a=p,U=1,i={id},p=1,x=0,y=0,w=30,h=10,c=3,r=1\x1b\\")
+        );
+
+        // Another placement of the same transmission.
+        let mut other = kitty.new_placement();
+        other
+            .resize_encode(&resize, background_color, Rect::new(0, 0, 1, 1))
+            .unwrap();
+        let KittyProtoState::TransmitAndPlace(Transmission { seq: data, .. }) = &other.proto_state
+        else {
+            panic!("should place");
+        };
+        assert_eq!(other.unique_id, id);
+        assert!(data.starts_with(&format!("\x1b_Gq=2,a=p,U=1,i={id},p=")));
+        assert!(!data.contains(",p=1,"));
+
+        early
+            .resize_encode(&resize, background_color, Rect::new(0, 0, 2, 2))
+            .unwrap();
+        let KittyProtoState::TransmitAndPlace(Transmission { seq: data, .. }) = &early.proto_state
+        else {
+            panic!("should place");
+        };
+        assert!(!data.contains("a=t"));
+        drop((kitty, other));
+        assert!(registry().live.contains(&id));
+        drop(early);
+        assert!(!registry().live.contains(&id));
+    }
+
+    #[test]
+    fn test_set_z_index() {
+        let mut kitty = Kitty::new(
+            DynamicImage::from(ImageBuffer::from_pixel(20, 20, Rgba([0u8, 0, 0, 255]))),
+            Rect::new(0, 0, 2, 2),
+            5,
+            false,
+            KittyOptions::default(),
+        )
+        .unwrap();
+        kitty.proto_state.make_transmit();
+
+        kitty.set_z_index(-1_073_741_825);
+        assert_eq!(
+            kitty.proto_state.make_transmit().unwrap(),
+            "\x1b_Gq=2,a=p,U=1,i=5,p=1,x=0,y=0,w=20,h=20,c=2,r=2,z=-1073741825\x1b\\"
+        );
+        kitty.set_z_index(-1_073_741_825);
+        assert!(kitty.proto_state.make_transmit().is_none());
+    }
+}
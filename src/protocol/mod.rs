@@ -3,14 +3,19 @@
 use std::{
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
+    sync::Arc,
+    time::Duration,
 };
 
-use image::{imageops, DynamicImage, ImageBuffer, Rgba};
+use image::{imageops, DynamicImage, Frames, ImageBuffer, Rgba};
 use ratatui::{buffer::Buffer, layout::Rect};
 
-use crate::FontSize;
+use crate::{errors::Errors, FontSize, Result};
 
 use self::{
+    ascii::{Ascii, StatefulAscii},
+    blocks::{Blocks, StatefulBlocks},
+    braille::{Braille, StatefulBraille},
     halfblocks::{Halfblocks, StatefulHalfblocks},
     iterm2::{Iterm2, StatefulIterm2},
     kitty::{Kitty, StatefulKitty},
@@ -19,6 +24,10 @@ use self::{
 
 use super::Resize;
 
+pub mod ascii;
+pub mod blocks;
+pub mod braille;
+pub mod color;
 pub mod halfblocks;
 pub mod iterm2;
 pub mod kitty;
@@ -48,13 +57,25 @@ trait StatefulProtocolTrait: ProtocolTrait {
     /// that next call for the given area does not need to redo the work.
     ///
     /// This can be done in a background thread, and the result is stored in this [StatefulProtocol].
-    fn resize_encode(&mut self, resize: &Resize, background_color: Rgba<u8>, area: Rect);
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()>;
+
+    /// The failure of the last [StatefulProtocol::resize_encode], if any.
+    fn last_error(&self) -> Option<&EncodeFailure>;
+    fn last_error_mut(&mut self) -> &mut Option<EncodeFailure>;
 }
 
 /// A fixed-size image protocol for the [crate::Image] widget.
 #[derive(Clone)]
 pub enum Protocol {
     Halfblocks(Halfblocks),
+    Blocks(Blocks),
+    Braille(Braille),
+    Ascii(Ascii),
     Sixel(Sixel),
     Kitty(Kitty),
     ITerm2(Iterm2),
@@ -63,6 +84,9 @@ impl Protocol {
     pub(crate) fn render(&mut self, area: Rect, buf: &mut Buffer) {
         let inner: &mut dyn ProtocolTrait = match self {
             Self::Halfblocks(halfblocks) => halfblocks,
+            Self::Blocks(blocks) => blocks,
+            Self::Braille(braille) => braille,
+            Self::Ascii(ascii) => ascii,
             Self::Sixel(sixel) => sixel,
             Self::Kitty(kitty) => kitty,
             Self::ITerm2(iterm2) => iterm2,
@@ -72,12 +96,35 @@ impl Protocol {
     pub fn area(&self) -> Rect {
         let inner: &dyn ProtocolTrait = match self {
             Self::Halfblocks(halfblocks) => halfblocks,
+            Self::Blocks(blocks) => blocks,
+            Self::Braille(braille) => braille,
+            Self::Ascii(ascii) => ascii,
             Self::Sixel(sixel) => sixel,
             Self::Kitty(kitty) => kitty,
             Self::ITerm2(iterm2) => iterm2,
         };
         inner.area()
     }
+
+    /// Set the z-index of the image relative to text and other images.
+    ///
+    /// Only the Kitty protocol supports this, for all others it has no effect.
+    pub fn set_z_index(&mut self, z_index: i32) {
+        if let Self::Kitty(kitty) = self {
+            kitty.set_z_index(z_index);
+        }
+    }
+
+    /// Another placement of the same image, to show it several times.
+    ///
+    /// Kitty transmits the image only once for all its placements, the original must have been
+    /// rendered before the new placement is. For all other protocols this is a plain clone.
+    pub fn new_placement(&self) -> Protocol {
+        match self {
+            Self::Kitty(kitty) => Self::Kitty(kitty.new_placement()),
+            _ => self.clone(),
+        }
+    }
 }
 
 /// A stateful resizing image protocol for the [crate::StatefulImage] widget.
@@ -87,6 +134,9 @@ impl Protocol {
 #[derive(Clone)]
 pub enum StatefulProtocol {
     Halfblocks(StatefulHalfblocks),
+    Blocks(StatefulBlocks),
+    Braille(StatefulBraille),
+    Ascii(StatefulAscii),
     Sixel(StatefulSixel),
     Kitty(StatefulKitty),
     ITerm2(StatefulIterm2),
@@ -95,6 +145,9 @@ impl StatefulProtocol {
     fn inner_trait(&self) -> &dyn StatefulProtocolTrait {
         match self {
             Self::Halfblocks(halfblocks) => halfblocks,
+            Self::Blocks(blocks) => blocks,
+            Self::Braille(braille) => braille,
+            Self::Ascii(ascii) => ascii,
             Self::Sixel(sixel) => sixel,
             Self::Kitty(kitty) => kitty,
             Self::ITerm2(iterm2) => iterm2,
@@ -103,6 +156,9 @@ impl StatefulProtocol {
     fn inner_trait_mut(&mut self) -> &mut dyn StatefulProtocolTrait {
         match self {
             Self::Halfblocks(halfblocks) => halfblocks,
+            Self::Blocks(blocks) => blocks,
+            Self::Braille(braille) => braille,
+            Self::Ascii(ascii) => ascii,
             Self::Sixel(sixel) => sixel,
             Self::Kitty(kitty) => kitty,
             Self::ITerm2(iterm2) => iterm2,
@@ -116,7 +172,8 @@ impl StatefulProtocol {
 
     /// Resize and encode if necessary, and render immediately.
     ///
-    /// This blocks the UI thread but requires neither threads nor async.
+    /// This blocks the UI thread but requires neither threads nor async. Nothing is rendered if
+    /// the encoding fails, see [StatefulProtocol::last_error].
     pub fn resize_encode_render(
         &mut self,
         resize: &Resize,
@@ -124,11 +181,10 @@ impl StatefulProtocol {
         area: Rect,
         buf: &mut Buffer,
     ) {
-        let proto = self.inner_trait_mut();
-        if let Some(rect) = proto.needs_resize(resize, area) {
-            proto.resize_encode(resize, background_color, rect);
+        if let Some(rect) = self.needs_resize(resize, area) {
+            let _ = self.resize_encode(resize, background_color, rect);
         }
-        proto.render(area, buf);
+        self.render(area, buf);
     }
 
     /// Check if the current image state would need resizing (grow or shrink) for the given area.
@@ -136,26 +192,87 @@ impl StatefulProtocol {
     /// This can be called by the UI thread to check if this [StatefulProtocol] should be sent off
     /// to some background thread/task to do the resizing and encoding, instead of rendering. The
     /// thread should then return the [StatefulProtocol] so that it can be rendered.protoco
+    ///
+    /// If encoding for the same area failed last time, it is not attempted again.
     pub fn needs_resize(&mut self, resize: &Resize, area: Rect) -> Option<Rect> {
-        self.inner_trait_mut().needs_resize(resize, area)
+        let proto = self.inner_trait_mut();
+        let rect = proto.needs_resize(resize, area)?;
+        if proto
+            .last_error()
+            .is_some_and(|failure| failure.area == rect)
+        {
+            return None;
+        }
+        Some(rect)
     }
 
     /// Resize the image and encode it for rendering. The result should be stored statefully so
     /// that next call for the given area does not need to redo the work.
     ///
     /// This can be done in a background thread, and the result is stored in this [StatefulProtocol].
-    pub fn resize_encode(&mut self, resize: &Resize, background_color: Rgba<u8>, area: Rect) {
-        self.inner_trait_mut()
-            .resize_encode(resize, background_color, area)
+    /// The error message is also kept until the next call, see [StatefulProtocol::last_error].
+    pub fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
+        let proto = self.inner_trait_mut();
+        let result = proto.resize_encode(resize, background_color, area);
+        *proto.last_error_mut() = result.as_ref().err().map(|err| EncodeFailure {
+            area,
+            message: err.to_string(),
+        });
+        result
+    }
+
+    /// The error message of the last [StatefulProtocol::resize_encode], if it failed.
+    pub fn last_error(&self) -> Option<&str> {
+        self.inner_trait()
+            .last_error()
+            .map(|failure| failure.message.as_str())
     }
 
     /// Render the currently resized and encoded data to the buffer.
+    ///
+    /// Nothing is rendered while the last [StatefulProtocol::resize_encode] has failed.
     pub fn render(&mut self, area: Rect, buf: &mut Buffer) {
-        self.inner_trait_mut().render(area, buf);
+        let proto = self.inner_trait_mut();
+        if proto.last_error().is_none() {
+            proto.render(area, buf);
+        }
     }
     pub fn area(&self) -> Rect {
         self.inner_trait().area()
     }
+
+    /// Set the z-index of the image relative to text and other images.
+    ///
+    /// Only the Kitty protocol supports this, for all others it has no effect.
+    pub fn set_z_index(&mut self, z_index: i32) {
+        if let Self::Kitty(kitty) = self {
+            kitty.set_z_index(z_index);
+        }
+    }
+
+    /// Another placement of the same image, to show it several times, possibly in other sizes.
+    ///
+    /// Kitty with [kitty::KittyOptions::server_side_scaling] transmits the image only once for
+    /// all its placements. For all other protocols this is a plain clone.
+    pub fn new_placement(&self) -> StatefulProtocol {
+        match self {
+            Self::Kitty(kitty) => Self::Kitty(kitty.new_placement()),
+            _ => self.clone(),
+        }
+    }
+}
+
+/// A failed [StatefulProtocol::resize_encode], kept by the protocol state.
+#[derive(Clone, Debug)]
+struct EncodeFailure {
+    /// The area that could not be encoded, so that it is not attempted again on every render.
+    area: Rect,
+    message: String,
 }
 
 #[derive(Clone)]
@@ -183,6 +300,17 @@ pub struct ImageSource {
     pub hash: u64,
     /// The background color that should be used for padding or background when resizing.
     pub background_color: Rgba<u8>,
+    pub(crate) frames: Vec<ImageFrame>,
+    pub(crate) encoded: Option<Arc<[u8]>>,
+}
+
+#[derive(Clone)]
+/// A single frame of an animated [ImageSource].
+pub struct ImageFrame {
+    /// The frame, composited onto the full canvas of the animation.
+    pub image: DynamicImage,
+    /// How long this frame should be shown before the next one.
+    pub delay: Duration,
 }
 
 impl ImageSource {
@@ -199,21 +327,90 @@ impl ImageSource {
         image.as_bytes().hash(&mut state);
         let hash = state.finish();
 
-        // We only need to underlay the background color here if it's not completely transparent.
-        if background_color.0[3] != 0 {
-            let mut bg: DynamicImage =
-                ImageBuffer::from_pixel(image.width(), image.height(), background_color).into();
-            imageops::overlay(&mut bg, &image, 0, 0);
-            image = bg;
-        }
+        image = underlay_background(image, background_color);
 
         ImageSource {
             image,
             desired,
             hash,
             background_color,
+            frames: vec![],
+            encoded: None,
         }
     }
+
+    /// Create a new image source from an encoded file (PNG, JPEG, ...).
+    ///
+    /// The file is kept, for protocols that can send it to the terminal as it is, see
+    /// [iterm2::Iterm2Encoding::Original].
+    pub fn new_encoded(
+        bytes: Vec<u8>,
+        font_size: FontSize,
+        background_color: Rgba<u8>,
+    ) -> Result<ImageSource> {
+        let image = image::load_from_memory(&bytes)?;
+        Ok(ImageSource {
+            encoded: Some(bytes.into()),
+            ..ImageSource::new(image, font_size, background_color)
+        })
+    }
+
+    /// Create a new image source from the frames of an animated image.
+    ///
+    /// All frames are decoded upfront. The first frame is used as [`ImageSource::image`], so that
+    /// protocols that cannot animate still show something sensible.
+    pub fn new_animated(
+        frames: Frames<'_>,
+        font_size: FontSize,
+        background_color: Rgba<u8>,
+    ) -> Result<ImageSource> {
+        let mut state = DefaultHasher::new();
+        let frames: Vec<ImageFrame> = frames
+            .collect_frames()?
+            .into_iter()
+            .map(|frame| {
+                let delay = Duration::from(frame.delay());
+                let image: DynamicImage = frame.into_buffer().into();
+                image.as_bytes().hash(&mut state);
+                ImageFrame {
+                    image: underlay_background(image, background_color),
+                    delay,
+                }
+            })
+            .collect();
+        let hash = state.finish();
+
+        let image = frames.first().ok_or(Errors::NoFrames)?.image.clone();
+        let desired =
+            ImageSource::round_pixel_size_to_cells(image.width(), image.height(), font_size);
+
+        Ok(ImageSource {
+            image,
+            desired,
+            hash,
+            background_color,
+            frames,
+            encoded: None,
+        })
+    }
+
+    /// All frames of an animated image, including the first one which is also
+    /// [`ImageSource::image`]. Empty for still images.
+    pub fn frames(&self) -> &[ImageFrame] {
+        &self.frames
+    }
+
+    /// The encoded file (PNG, JPEG, ...) that the image was decoded from, if it was created with
+    /// [`ImageSource::new_encoded`].
+    pub fn encoded(&self) -> Option<&[u8]> {
+        self.encoded.as_deref()
+    }
+
+    /// Whether this source has more than one frame.
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+
     /// Round an image pixel size to the nearest matching cell size, given a font size.
     pub fn round_pixel_size_to_cells(
         img_width: u32,
@@ -225,3 +422,31 @@ impl ImageSource {
         Rect::new(0, 0, width, height)
     }
 }
+
+/// Erase the characters of an area, starting at the cursor position, and move back to the start.
+///
+/// Transparency needs explicit erasing of stale characters, or they stay behind the rendered
+/// image due to skipping of the following characters _in the buffer_.
+/// DECERA does not work in WezTerm, however ECH and and cursor CUD and CUU do.
+/// For each line, erase `width` characters, then move back and place image.
+fn erase_area(escape: &str, area: Rect) -> String {
+    let width = area.width;
+    let height = area.height;
+    let mut seq = String::new();
+    for _ in 0..height {
+        seq.push_str(&format!("{escape}[{width}X{escape}[1B"));
+    }
+    seq.push_str(&format!("{escape}[{height}A"));
+    seq
+}
+
+fn underlay_background(image: DynamicImage, background_color: Rgba<u8>) -> DynamicImage {
+    // We only need to underlay the background color here if it's not completely transparent.
+    if background_color.0[3] == 0 {
+        return image;
+    }
+    let mut bg: DynamicImage =
+        ImageBuffer::from_pixel(image.width(), image.height(), background_color).into();
+    imageops::overlay(&mut bg, &image, 0, 0);
+    bg
+}
@@ -1,64 +1,76 @@
 //! Sixel protocol implementations.
-//! Uses [`sixel-bytes`] to draw image pixels, if the terminal [supports] the [Sixel] protocol.
-//! Needs the `sixel` feature.
+//! Uses a built-in encoder to draw image pixels, if the terminal [supports] the [Sixel] protocol.
+//! Transparent pixels are left undrawn. The quantization and dithering can be tuned with
+//! [SixelOptions].
 //!
-//! [`sixel-bytes`]: https://github.com/benjajaja/sixel-bytes
 //! [supports]: https://arewesixelyet.com
 //! [Sixel]: https://en.wikipedia.org/wiki/Sixel
-use icy_sixel::{
-    sixel_string, DiffusionMethod, MethodForLargest, MethodForRep, PixelFormat, Quality,
-};
-use image::{DynamicImage, Rgba};
+use image::{imageops::FilterType, DynamicImage, Rgba};
 use ratatui::{buffer::Buffer, layout::Rect};
 use std::cmp::min;
 
-use super::{ProtocolTrait, StatefulProtocolTrait};
+use super::{erase_area, EncodeFailure, ProtocolTrait, StatefulProtocolTrait};
 use crate::{errors::Errors, picker::cap_parser::Parser, FontSize, ImageSource, Resize, Result};
 
+mod encoder;
+
+pub use encoder::{Dither, Quantizer, SixelOptions, MAX_COLORS};
+
 // Fixed sixel protocol
 #[derive(Clone, Default)]
 pub struct Sixel {
     pub data: String,
     pub area: Rect,
     pub is_tmux: bool,
+    pub options: SixelOptions,
 }
 
 impl Sixel {
-    pub fn new(image: DynamicImage, area: Rect, is_tmux: bool) -> Result<Self> {
-        let data = encode(&image, is_tmux)?;
+    pub fn new(
+        image: DynamicImage,
+        area: Rect,
+        is_tmux: bool,
+        options: SixelOptions,
+    ) -> Result<Self> {
+        let data = encode(&image, area, is_tmux, &options)?;
         Ok(Self {
             data,
             area,
             is_tmux,
+            options,
         })
     }
 }
 
-// TODO: change E to sixel_rs::status::Error and map when calling
-fn encode(img: &DynamicImage, is_tmux: bool) -> Result<String> {
-    let (w, h) = (img.width(), img.height());
-    let img_rgb8 = img.to_rgb8();
-    let bytes = img_rgb8.as_raw();
-
-    let mut data = sixel_string(
-        bytes,
-        w as i32,
-        h as i32,
-        PixelFormat::RGB888,
-        DiffusionMethod::Stucki,
-        MethodForLargest::Auto,
-        MethodForRep::Auto,
-        Quality::HIGH,
-    )
-    .map_err(|err| Errors::Sixel(err.to_string()))?;
+fn encode(
+    img: &DynamicImage,
+    render_area: Rect,
+    is_tmux: bool,
+    options: &SixelOptions,
+) -> Result<String> {
+    let img_rgba8 = match options.max_size {
+        Some((w, h)) if img.width() > w as u32 || img.height() > h as u32 => img
+            .resize(w as u32, h as u32, FilterType::Triangle)
+            .to_rgba8(),
+        _ => img.to_rgba8(),
+    };
 
+    let transparent = encoder::has_transparency(&img_rgba8, options.alpha_threshold);
+    let mut data = encoder::encode(&img_rgba8, options);
+
+    let (start, escape, end) = Parser::escape_tmux(is_tmux);
     if is_tmux {
-        let (start, escape, end) = Parser::escape_tmux(is_tmux);
         if data.strip_prefix('\x1b').is_none() {
             return Err(Errors::Tmux("sixel string did not start with escape"));
         }
 
         data.insert_str(0, escape);
+    }
+    if transparent {
+        // Undrawn pixels would show stale characters behind the image.
+        data.insert_str(0, &erase_area(escape, render_area));
+    }
+    if is_tmux {
         data.insert_str(0, start);
         data.push_str(end);
     }
@@ -137,18 +149,26 @@ pub struct StatefulSixel {
     font_size: FontSize,
     current: Sixel,
     hash: u64,
+    last_error: Option<EncodeFailure>,
 }
 
 impl StatefulSixel {
-    pub fn new(source: ImageSource, font_size: FontSize, is_tmux: bool) -> StatefulSixel {
+    pub fn new(
+        source: ImageSource,
+        font_size: FontSize,
+        is_tmux: bool,
+        options: SixelOptions,
+    ) -> StatefulSixel {
         StatefulSixel {
             source,
             font_size,
             current: Sixel {
                 is_tmux,
+                options,
                 ..Sixel::default()
             },
             hash: u64::default(),
+            last_error: None,
         }
     }
 }
@@ -176,25 +196,52 @@ impl StatefulProtocolTrait for StatefulSixel {
             self.source.hash != self.hash,
         )
     }
-    fn resize_encode(&mut self, resize: &Resize, background_color: Rgba<u8>, area: Rect) {
+    fn resize_encode(
+        &mut self,
+        resize: &Resize,
+        background_color: Rgba<u8>,
+        area: Rect,
+    ) -> Result<()> {
         if area.width == 0 || area.height == 0 {
-            return;
+            return Ok(());
         }
 
         let img = resize.resize(&self.source, self.font_size, area, background_color);
         let is_tmux = self.current.is_tmux;
-        match encode(&img, is_tmux) {
-            Ok(data) => {
-                self.current = Sixel {
-                    data,
-                    area,
-                    is_tmux,
-                };
-                self.hash = self.source.hash;
-            }
-            Err(_err) => {
-                // TODO: save err in struct and expose in trait?
-            }
-        }
+        let options = self.current.options;
+        let data = encode(&img, area, is_tmux, &options)?;
+        self.current = Sixel {
+            data,
+            area,
+            is_tmux,
+            options,
+        };
+        self.hash = self.source.hash;
+        Ok(())
+    }
+    fn last_error(&self) -> Option<&EncodeFailure> {
+        self.last_error.as_ref()
+    }
+    fn last_error_mut(&mut self) -> &mut Option<EncodeFailure> {
+        &mut self.last_error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use ratatui::layout::Rect;
+
+    use super::{encode, SixelOptions};
+
+    #[test]
+    fn test_encode_max_size() {
+        let img = DynamicImage::from(ImageBuffer::from_pixel(40, 20, Rgba([255u8, 0, 0, 255])));
+        let options = SixelOptions {
+            max_size: Some((10, 10)),
+            ..SixelOptions::default()
+        };
+        let data = encode(&img, Rect::new(0, 0, 4, 2), false, &options).unwrap();
+        assert!(data.starts_with("\x1bP0;1;0q\"1;1;10;5"), "{data:?}");
     }
 }
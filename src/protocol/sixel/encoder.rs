@@ -0,0 +1,555 @@
+//! Native sixel encoder.
+//!
+//! The image is quantized to a palette with one of the [Quantizer]s, optionally dithered, and
+//! written out band by band, one color at a time.
+//!
+//! The DCS sequence sets `P2=1` ("background select"), which makes the terminal leave any pixel
+//! that is not painted by some color register as it is. Pixels with an alpha below the threshold
+//! are not painted by any color, so all color registers go to the opaque pixels.
+//!
+//! See https://vt100.net/docs/vt3xx-gp/chapter14.html
+use std::{collections::HashMap, fmt::Write};
+
+use image::RgbaImage;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Most terminals have 256 color registers.
+pub const MAX_COLORS: u16 = 256;
+
+/// Refinement passes of [Quantizer::KMeans].
+const KMEANS_ITERATIONS: usize = 6;
+
+type Rgb = [u8; 3];
+
+/// Options for the sixel encoder.
+///
+/// The defaults give good quality. For small thumbnails, fewer colors and [Dither::None] or
+/// [Dither::Ordered] encode a lot faster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct SixelOptions {
+    /// Palette size, the number of color registers used. Capped at [MAX_COLORS].
+    pub colors: u16,
+    pub quantizer: Quantizer,
+    pub dither: Dither,
+    /// Compress runs of identical sixels with the graphics repeat introducer.
+    pub rle: bool,
+    /// Pixels with an alpha value below this are left undrawn.
+    pub alpha_threshold: u8,
+    /// Largest image in pixels that the terminal accepts, larger images are scaled down to fit.
+    /// Some terminals, like xterm, silently drop oversize sixels.
+    pub max_size: Option<(u16, u16)>,
+}
+
+impl Default for SixelOptions {
+    fn default() -> Self {
+        SixelOptions {
+            colors: MAX_COLORS,
+            quantizer: Quantizer::MedianCut,
+            dither: Dither::FloydSteinberg,
+            rle: true,
+            alpha_threshold: 128,
+            max_size: None,
+        }
+    }
+}
+
+/// Palette selection method.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Quantizer {
+    /// Recursively split the color space at the median of the widest channel. Fast and good.
+    MedianCut,
+    /// Merge colors that share a prefix of bits in each channel. Fastest, but coarser.
+    Octree,
+    /// Median-cut refined with k-means passes. Best palette, but slowest.
+    KMeans,
+}
+
+/// Dithering method, applied when mapping pixels to the palette.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize, Serialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Dither {
+    /// Map every pixel to the nearest palette color.
+    None,
+    /// Error diffusion to four neighbours.
+    FloydSteinberg,
+    /// Error diffusion of three quarters of the error to six neighbours, keeps more contrast.
+    Atkinson,
+    /// 4x4 Bayer matrix threshold. Fast, and stable between frames.
+    Ordered,
+}
+
+/// Encode an image as a sixel string.
+///
+/// At most `options.colors` colors are used. Transparent pixels are left unpainted.
+pub fn encode(img: &RgbaImage, options: &SixelOptions) -> String {
+    let max_colors = options.colors.clamp(1, MAX_COLORS) as usize;
+    let histogram = histogram(img, options.alpha_threshold);
+    let palette = match options.quantizer {
+        Quantizer::MedianCut => median_cut(histogram, max_colors),
+        Quantizer::Octree => octree(&histogram, max_colors),
+        Quantizer::KMeans => {
+            let palette = median_cut(histogram.clone(), max_colors);
+            kmeans(&histogram, palette)
+        }
+    };
+    let indices = map_pixels(img, &palette, options);
+
+    let mut data = String::new();
+    write_sixel(
+        &mut data,
+        img.width() as usize,
+        img.height() as usize,
+        &palette,
+        &indices,
+        options.rle,
+    );
+    data
+}
+
+/// Whether any pixel would be left undrawn by [encode].
+pub fn has_transparency(img: &RgbaImage, alpha_threshold: u8) -> bool {
+    img.pixels().any(|pixel| pixel[3] < alpha_threshold)
+}
+
+/// Unique colors of the opaque pixels, with their pixel counts.
+fn histogram(img: &RgbaImage, alpha_threshold: u8) -> Vec<(Rgb, u32)> {
+    let mut histogram: HashMap<Rgb, u32> = HashMap::new();
+    for pixel in img.pixels() {
+        if pixel[3] >= alpha_threshold {
+            *histogram.entry([pixel[0], pixel[1], pixel[2]]).or_default() += 1;
+        }
+    }
+    histogram.into_iter().collect()
+}
+
+fn median_cut(histogram: Vec<(Rgb, u32)>, max_colors: usize) -> Vec<Rgb> {
+    let mut boxes = vec![histogram];
+    while boxes.len() < max_colors {
+        // Split the box with the widest channel range.
+        let Some((i, channel, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .map(|(i, colors)| {
+                let (channel, range) = widest_channel(colors);
+                (i, channel, range)
+            })
+            .max_by_key(|(_, _, range)| *range)
+        else {
+            break;
+        };
+        let mut colors = boxes.swap_remove(i);
+        colors.sort_unstable_by_key(|(color, _)| color[channel]);
+
+        // Split at the weighted median, but leave at least one color on each side.
+        let total: u64 = colors.iter().map(|(_, count)| *count as u64).sum();
+        let mut acc = 0;
+        let mut median = 1;
+        for (j, (_, count)) in colors.iter().enumerate() {
+            acc += *count as u64;
+            if acc * 2 >= total {
+                median = j.clamp(1, colors.len() - 1);
+                break;
+            }
+        }
+        let upper = colors.split_off(median);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    boxes
+        .iter()
+        .filter(|colors| !colors.is_empty())
+        .map(|colors| average(colors.iter().copied()))
+        .collect()
+}
+
+fn widest_channel(colors: &[(Rgb, u32)]) -> (usize, u8) {
+    let mut min = [u8::MAX; 3];
+    let mut max = [u8::MIN; 3];
+    for (color, _) in colors {
+        for c in 0..3 {
+            min[c] = min[c].min(color[c]);
+            max[c] = max[c].max(color[c]);
+        }
+    }
+    (0..3)
+        .map(|c| (c, max[c] - min[c]))
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+/// Octree quantization: take the deepest tree level that fits into the palette, then split the
+/// most populated nodes into their children while there is room left.
+fn octree(histogram: &[(Rgb, u32)], max_colors: usize) -> Vec<Rgb> {
+    let key = |color: &Rgb, depth: u32| -> u32 {
+        if depth == 0 {
+            return 0;
+        }
+        let [r, g, b] = color.map(|c| (c >> (8 - depth)) as u32);
+        (r << (2 * depth)) | (g << depth) | b
+    };
+    let level = |depth: u32| -> HashMap<u32, Vec<(Rgb, u32)>> {
+        let mut nodes: HashMap<u32, Vec<(Rgb, u32)>> = HashMap::new();
+        for (color, count) in histogram {
+            nodes
+                .entry(key(color, depth))
+                .or_default()
+                .push((*color, *count));
+        }
+        nodes
+    };
+
+    let mut depth = 0;
+    let mut nodes = level(0);
+    while depth < 8 {
+        let deeper = level(depth + 1);
+        if deeper.len() > max_colors {
+            break;
+        }
+        depth += 1;
+        nodes = deeper;
+    }
+
+    let mut leaves: Vec<Vec<(Rgb, u32)>> = nodes.into_values().collect();
+    if depth < 8 {
+        leaves.sort_unstable_by_key(|colors| {
+            std::cmp::Reverse(colors.iter().map(|(_, count)| *count as u64).sum::<u64>())
+        });
+        let mut i = 0;
+        while i < leaves.len() {
+            let mut children: HashMap<u32, Vec<(Rgb, u32)>> = HashMap::new();
+            for (color, count) in &leaves[i] {
+                children
+                    .entry(key(color, depth + 1))
+                    .or_default()
+                    .push((*color, *count));
+            }
+            if children.len() > 1 && leaves.len() + children.len() - 1 <= max_colors {
+                leaves.swap_remove(i);
+                leaves.extend(children.into_values());
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    leaves
+        .iter()
+        .filter(|colors| !colors.is_empty())
+        .map(|colors| average(colors.iter().copied()))
+        .collect()
+}
+
+/// Refine a palette by moving each color to the average of the colors that map to it.
+fn kmeans(histogram: &[(Rgb, u32)], mut palette: Vec<Rgb>) -> Vec<Rgb> {
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut clusters: Vec<Vec<(Rgb, u32)>> = vec![vec![]; palette.len()];
+        for (color, count) in histogram {
+            clusters[nearest(&palette, *color) as usize].push((*color, *count));
+        }
+        let next: Vec<Rgb> = clusters
+            .iter()
+            .zip(&palette)
+            .map(|(colors, color)| {
+                if colors.is_empty() {
+                    *color
+                } else {
+                    average(colors.iter().copied())
+                }
+            })
+            .collect();
+        if next == palette {
+            break;
+        }
+        palette = next;
+    }
+    palette
+}
+
+fn average(colors: impl Iterator<Item = (Rgb, u32)>) -> Rgb {
+    let mut total = 0u64;
+    let mut sum = [0u64; 3];
+    for (color, count) in colors {
+        total += count as u64;
+        for c in 0..3 {
+            sum[c] += color[c] as u64 * count as u64;
+        }
+    }
+    sum.map(|s| ((s + total / 2) / total.max(1)) as u8)
+}
+
+fn nearest(palette: &[Rgb], color: Rgb) -> u8 {
+    let mut best = 0;
+    let mut best_distance = u32::MAX;
+    for (i, candidate) in palette.iter().enumerate() {
+        let distance: u32 = (0..3)
+            .map(|c| (candidate[c] as i32 - color[c] as i32).pow(2) as u32)
+            .sum();
+        if distance < best_distance {
+            best = i;
+            best_distance = distance;
+        }
+    }
+    best as u8
+}
+
+/// Nearest palette color lookup, cached at 6 bits per channel.
+struct NearestCache<'a> {
+    palette: &'a [Rgb],
+    cache: Vec<u16>,
+}
+
+impl NearestCache<'_> {
+    fn new(palette: &[Rgb]) -> NearestCache<'_> {
+        NearestCache {
+            palette,
+            cache: vec![u16::MAX; 1 << 18],
+        }
+    }
+    fn get(&mut self, color: Rgb) -> u8 {
+        let [r, g, b] = color.map(|c| (c >> 2) as usize);
+        let key = (r << 12) | (g << 6) | b;
+        if self.cache[key] == u16::MAX {
+            self.cache[key] = nearest(self.palette, color) as u16;
+        }
+        self.cache[key] as u8
+    }
+}
+
+/// The palette index of every pixel, where `None` is a transparent pixel that is not painted.
+fn map_pixels(img: &RgbaImage, palette: &[Rgb], options: &SixelOptions) -> Vec<Option<u8>> {
+    const BAYER: [[f32; 4]; 4] = [
+        [0.0, 8.0, 2.0, 10.0],
+        [12.0, 4.0, 14.0, 6.0],
+        [3.0, 11.0, 1.0, 9.0],
+        [15.0, 7.0, 13.0, 5.0],
+    ];
+    // (dx, dy, weight) of the error diffusion kernels.
+    const FLOYD_STEINBERG: &[(isize, usize, f32)] = &[
+        (1, 0, 7.0 / 16.0),
+        (-1, 1, 3.0 / 16.0),
+        (0, 1, 5.0 / 16.0),
+        (1, 1, 1.0 / 16.0),
+    ];
+    const ATKINSON: &[(isize, usize, f32)] = &[
+        (1, 0, 1.0 / 8.0),
+        (2, 0, 1.0 / 8.0),
+        (-1, 1, 1.0 / 8.0),
+        (0, 1, 1.0 / 8.0),
+        (1, 1, 1.0 / 8.0),
+        (0, 2, 1.0 / 8.0),
+    ];
+
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let mut cache = NearestCache::new(palette);
+    let opaque =
+        |x: usize, y: usize| img.get_pixel(x as u32, y as u32)[3] >= options.alpha_threshold;
+
+    if palette.is_empty() {
+        return vec![None; width * height];
+    }
+
+    match options.dither {
+        Dither::None | Dither::Ordered => {
+            // Spread the threshold over roughly the distance between palette colors.
+            let spread = 255.0 / (palette.len() as f32).cbrt();
+            img.enumerate_pixels()
+                .map(|(x, y, pixel)| {
+                    if pixel[3] < options.alpha_threshold {
+                        return None;
+                    }
+                    let mut color = [pixel[0], pixel[1], pixel[2]];
+                    if options.dither == Dither::Ordered {
+                        let threshold = BAYER[y as usize % 4][x as usize % 4] / 16.0 - 0.5;
+                        color =
+                            color.map(|c| (c as f32 + threshold * spread).clamp(0.0, 255.0) as u8);
+                    }
+                    Some(cache.get(color))
+                })
+                .collect()
+        }
+        Dither::FloydSteinberg | Dither::Atkinson => {
+            let kernel = if options.dither == Dither::FloydSteinberg {
+                FLOYD_STEINBERG
+            } else {
+                ATKINSON
+            };
+            let mut colors: Vec<[f32; 3]> = img
+                .pixels()
+                .map(|pixel| [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32])
+                .collect();
+            let mut indices = vec![None; width * height];
+            for y in 0..height {
+                for x in 0..width {
+                    if !opaque(x, y) {
+                        continue;
+                    }
+                    let i = y * width + x;
+                    let color = colors[i].map(|c| c.clamp(0.0, 255.0) as u8);
+                    let index = cache.get(color);
+                    indices[i] = Some(index);
+
+                    let mapped = palette[index as usize];
+                    let error: [f32; 3] = [0, 1, 2].map(|c| colors[i][c] - mapped[c] as f32);
+                    for (dx, dy, weight) in kernel {
+                        let (nx, ny) = (x as isize + dx, y + dy);
+                        if nx < 0 || nx as usize >= width || ny >= height {
+                            continue;
+                        }
+                        // Don't diffuse into transparent pixels.
+                        if !opaque(nx as usize, ny) {
+                            continue;
+                        }
+                        let neighbour = &mut colors[ny * width + nx as usize];
+                        for c in 0..3 {
+                            neighbour[c] += error[c] * weight;
+                        }
+                    }
+                }
+            }
+            indices
+        }
+    }
+}
+
+/// Write the sixel DCS sequence for already quantized pixels.
+fn write_sixel(
+    data: &mut String,
+    width: usize,
+    height: usize,
+    palette: &[Rgb],
+    indices: &[Option<u8>],
+    rle: bool,
+) {
+    // P2=1: pixels that are not painted keep their current color.
+    // Raster attributes: 1:1 pixel aspect ratio, and the image size.
+    write!(data, "\x1bP0;1;0q\"1;1;{width};{height}").unwrap();
+
+    for (i, [r, g, b]) in palette.iter().enumerate() {
+        // Color components are percentages.
+        let [r, g, b] = [r, g, b].map(|c| (*c as u32 * 100 + 127) / 255);
+        write!(data, "#{i};2;{r};{g};{b}").unwrap();
+    }
+
+    let mut sixels = vec![0u8; width];
+    for band in (0..height).step_by(6) {
+        let rows = (height - band).min(6);
+        let mut used = vec![false; palette.len()];
+        for index in indices[band * width..(band + rows) * width]
+            .iter()
+            .flatten()
+        {
+            used[*index as usize] = true;
+        }
+
+        let mut first = true;
+        for (color, _) in used.iter().enumerate().filter(|(_, used)| **used) {
+            sixels.fill(0);
+            for row in 0..rows {
+                let offset = (band + row) * width;
+                for (x, sixel) in sixels.iter_mut().enumerate() {
+                    if indices[offset + x] == Some(color as u8) {
+                        *sixel |= 1 << row;
+                    }
+                }
+            }
+
+            if !first {
+                // Graphics carriage return, paint the next color over the same band.
+                data.push('$');
+            }
+            first = false;
+            write!(data, "#{color}").unwrap();
+            write_runs(data, &sixels, rle);
+        }
+        // Graphics new line, move to the next band.
+        data.push('-');
+    }
+
+    data.push_str("\x1b\\");
+}
+
+/// Write sixel characters, compressing runs with the graphics repeat introducer `!` if `rle`.
+fn write_runs(data: &mut String, sixels: &[u8], rle: bool) {
+    // Trailing empty sixels don't need to be written.
+    let len = sixels.iter().rposition(|s| *s != 0).map_or(0, |i| i + 1);
+    let mut x = 0;
+    while x < len {
+        let sixel = sixels[x];
+        let run = sixels[x..len].iter().take_while(|s| **s == sixel).count();
+        let ch = char::from(0x3f + sixel);
+        if rle && run > 3 {
+            write!(data, "!{run}{ch}").unwrap();
+        } else {
+            for _ in 0..run {
+                data.push(ch);
+            }
+        }
+        x += run;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::{encode, Dither, Quantizer, SixelOptions};
+
+    #[test]
+    fn test_encode_transparent() {
+        let mut img = RgbaImage::from_pixel(8, 7, Rgba([255, 0, 0, 255]));
+        for x in 0..8 {
+            img.put_pixel(x, 0, Rgba([0, 0, 0, 0]));
+        }
+        img.put_pixel(7, 6, Rgba([0, 0, 255, 255]));
+        let options = SixelOptions::default();
+        assert_eq!(
+            encode(&img, &options),
+            "\x1bP0;1;0q\"1;1;8;7#0;2;100;0;0#1;2;0;0;100#0!8}-#0!7@$#1!7?@-\x1b\\"
+        );
+        let options = SixelOptions {
+            rle: false,
+            ..options
+        };
+        assert_eq!(
+            encode(&img, &options),
+            "\x1bP0;1;0q\"1;1;8;7#0;2;100;0;0#1;2;0;0;100#0}}}}}}}}-#0@@@@@@@$#1???????@-\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_palette_size() {
+        let img = RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba([(x * 4) as u8, (y * 4) as u8, 128, 255])
+        });
+        for quantizer in [Quantizer::MedianCut, Quantizer::Octree, Quantizer::KMeans] {
+            for dither in [
+                Dither::None,
+                Dither::FloydSteinberg,
+                Dither::Atkinson,
+                Dither::Ordered,
+            ] {
+                let options = SixelOptions {
+                    colors: 16,
+                    quantizer,
+                    dither,
+                    ..SixelOptions::default()
+                };
+                let colors = encode(&img, &options).matches(";2;").count();
+                assert!((2..=16).contains(&colors), "{quantizer:?} {dither:?}");
+            }
+        }
+    }
+}